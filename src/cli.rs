@@ -0,0 +1,68 @@
+use crate::listener::ListenAddress;
+use clap::Parser;
+use std::path::PathBuf;
+
+/// Spartan server
+#[derive(Parser, Clone, Debug, Default)]
+#[command(version, about)]
+pub struct Args {
+    /// Configuration file(replaces ./settings.toml and /etc/rustan/settings.toml)
+    #[arg(short, long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Listening address, for example 127.0.0.1:3000, [::]:300 or unix:/run/rustan.sock(can be repeated)
+    #[arg(short, long, value_name = "ADDRESS")]
+    pub listen: Vec<ListenAddress>,
+
+    /// Directory with hosts
+    #[arg(short, long, value_name = "PATH")]
+    pub root: Option<String>,
+
+    /// Limit of upload data in bytes
+    #[arg(long, value_name = "BYTES")]
+    pub max_upload_size: Option<usize>,
+
+    /// Load and validate configuration, print it and exit
+    #[arg(long)]
+    pub check_config: bool,
+}
+
+// ----------------- Tests section --------------------
+
+#[test]
+fn parse_all_arguments() {
+    let args = Args::try_parse_from([
+        "rustan",
+        "--config",
+        "/etc/spartan.toml",
+        "--listen",
+        "127.0.0.1:3000",
+        "--listen",
+        "unix:/run/rustan.sock",
+        "--root",
+        "/var/spartan",
+        "--max-upload-size",
+        "1024",
+        "--check-config",
+    ])
+    .unwrap();
+
+    assert_eq!(args.config, Some(PathBuf::from("/etc/spartan.toml")));
+    assert_eq!(
+        args.listen,
+        vec![
+            "127.0.0.1:3000".parse().unwrap(),
+            "unix:/run/rustan.sock".parse().unwrap()
+        ]
+    );
+    assert_eq!(args.root, Some("/var/spartan".to_string()));
+    assert_eq!(args.max_upload_size, Some(1024));
+    assert!(args.check_config);
+}
+
+#[test]
+fn parse_wrong_upload_size() {
+    let args = Args::try_parse_from(["rustan", "--max-upload-size", "a lot"]);
+
+    assert!(args.is_err());
+}
@@ -1,79 +1,709 @@
 use std::{collections::HashMap, fmt::Display};
 
+use crate::cli::Args;
 use crate::error::{Error, Result};
-use config::Config;
+use crate::listener::{one_or_many, ListenAddress};
+use crate::mime::normalize_extension;
+use crate::pipe::connection::BUFFER_SIZE;
+use crate::pipe::directory::DirectoryListing;
+use crate::pipe::error_page::ErrorPages;
+use crate::pipe::hidden::HiddenPattern;
+use crate::pipe::redirect::Redirect;
+use crate::pipe::resolver::SymlinkPolicy;
+use crate::pipe::vhost::{build_host_table, HOST_TABLE};
+use crate::privileges::is_chrooted;
+use config::{Config, ConfigError, FileFormat};
 use lazy_static::lazy_static;
+use log::{info, warn};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use tokio::sync::RwLock;
 
-#[derive(Clone)]
+/// Settings that belongs to exact virtual host. Missing ones are taken from global settings
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfiguration {
+    #[serde(default)]
+    pub redirects: Vec<Redirect>,
+    /// Other names of host that are served from same directory
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// Directory of host instead of one inside of server root
+    pub root: Option<String>,
+    pub max_upload_size: Option<usize>,
+    pub cgi: Option<bool>,
+    pub directory_listing: Option<DirectoryListing>,
+    /// Appended to text types as "charset=..."
+    pub charset: Option<String>,
+    /// Appended to text types as "lang=..."
+    pub lang: Option<String>,
+    /// Own status lines for errors, missing ones are taken from global ones
+    #[serde(default)]
+    pub errors: ErrorPages,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Configuration {
-    pub host: String,
+    /// Listening addresses(TCP or Unix sockets)
+    #[serde(rename = "host", deserialize_with = "one_or_many")]
+    pub listen: Vec<ListenAddress>,
+    #[serde(rename = "server_root")]
     pub root_path: String,
     pub max_upload_size: usize,
+    /// Executables are run as CGI scripts
+    pub cgi: bool,
+    /// When directories without index file are listed
+    pub directory_listing: DirectoryListing,
+    /// Appended to text types as "charset=..." for hosts without own charset
+    pub charset: Option<String>,
+    /// How symlinks inside of hosts are followed
+    pub symlink_policy: SymlinkPolicy,
+    /// Files starting with dot aren't served or listed
+    pub hide_dotfiles: bool,
+    /// Globs for files that aren't served or listed
+    pub hidden_files: Vec<HiddenPattern>,
+    /// Extension to mime type, checked before builtin and guessed types
+    pub mime_types: HashMap<String, String>,
+    /// Status lines for errors of hosts without own ones
+    pub errors: ErrorPages,
+    /// Bytes in request line(without CRLF)
+    pub max_request_line: usize,
+    /// Seconds for receiving request line
+    pub request_timeout: u64,
+    /// Seconds for receiving uploaded data
+    pub body_timeout: u64,
+    /// Seconds that client can don't read response
+    pub write_timeout: u64,
+    /// Seconds for finishing active connections on shutdown
+    pub shutdown_timeout: u64,
+    /// User that server runs as after binding listeners
+    pub user: Option<String>,
+    /// Group that server runs as(primary group of user by default)
+    pub group: Option<String>,
+    /// Directory that becomes root after binding listeners. Server root is inside it
+    pub chroot: Option<String>,
+    pub hosts: HashMap<String, HostConfiguration>,
+    /// Files that were read, as they're seen on reload(inside of chroot if it's set)
+    #[serde(skip)]
+    pub files: Vec<PathBuf>,
 }
 
 impl Default for Configuration {
     fn default() -> Self {
         Self {
-            host: "0.0.0.0:300".to_string(),
+            listen: vec![ListenAddress::Tcp(SocketAddr::from(([0, 0, 0, 0], 300)))],
             root_path: "./".to_string(),
             max_upload_size: 8388608,
+            cgi: true,
+            directory_listing: DirectoryListing::default(),
+            charset: None,
+            symlink_policy: SymlinkPolicy::default(),
+            hide_dotfiles: true,
+            hidden_files: ["*~", "*.bak"]
+                .into_iter()
+                .map(|p| HiddenPattern::try_from(p.to_string()).unwrap())
+                .collect(),
+            mime_types: HashMap::new(),
+            errors: ErrorPages::default(),
+            max_request_line: BUFFER_SIZE,
+            request_timeout: 10,
+            body_timeout: 60,
+            write_timeout: 60,
+            shutdown_timeout: 30,
+            user: None,
+            group: None,
+            chroot: None,
+            hosts: HashMap::new(),
+            files: Vec::new(),
         }
     }
 }
 
 impl Configuration {
-    pub fn new(host: String, root_path: String, max_upload_size: usize) -> Self {
+    pub fn new(listen: Vec<ListenAddress>, root_path: String, max_upload_size: usize) -> Self {
         Self {
-            host,
+            listen,
             root_path,
             max_upload_size,
+            ..Self::default()
         }
     }
 
-    pub fn load_from_config() -> Result<Self> {
-        let settings = io_err!(Config::builder()
-            .add_source(config::File::with_name("./settings.toml").required(false))
-            .add_source(config::File::with_name("/etc/rustan/settings.toml").required(false))
-            .add_source(config::Environment::with_prefix("RUSTAN"))
-            .build())?;
+    pub fn host_configuration(&self, host: &str) -> Option<&HostConfiguration> {
+        self.hosts.get(host)
+    }
 
-        let values = io_err!(settings.try_deserialize::<HashMap<String, String>>())?;
+    /// Path as it seen before chroot
+    fn before_chroot(&self, path: &str) -> PathBuf {
+        match &self.chroot {
+            Some(chroot) if !is_chrooted() => Path::new(chroot).join(path.trim_start_matches('/')),
+            _ => PathBuf::from(path),
+        }
+    }
 
-        let host = values
-            .get("host")
-            .map(String::from)
-            .unwrap_or_else(|| "0.0.0.0:300".to_string());
+    /// Path of configuration file for reload. None if file stays outside of chroot
+    fn reload_path(&self, file: &Path) -> Option<PathBuf> {
+        let path = file.canonicalize().ok()?;
 
-        let root_path = values
-            .get("server_root")
-            .map(String::from)
-            .unwrap_or_else(|| "./".to_string());
+        match &self.chroot {
+            Some(chroot) if !is_chrooted() => {
+                let root = Path::new(chroot).canonicalize().ok()?;
 
-        let max_upload_size = io_err!(values
-            .get("max_upload_size")
-            .map(|s| s.parse::<usize>())
-            .unwrap_or_else(|| Ok(4096)))?;
+                path.strip_prefix(root).ok().map(|p| Path::new("/").join(p))
+            }
+            _ => Some(path),
+        }
+    }
 
-        Ok(Self {
-            host,
-            root_path,
-            max_upload_size,
-        })
+    /// Checks things that can't be checked while parsing
+    pub fn validate(&self) -> Result<()> {
+        if let Some(chroot) = self.chroot.as_ref().filter(|_| !is_chrooted()) {
+            if !Path::new(chroot).is_dir() {
+                return Err(Error::new_other(
+                    format!("Chroot {} isn't a directory", chroot).as_str(),
+                ));
+            }
+        }
+
+        if !self.before_chroot(self.root_path.as_str()).is_dir() {
+            return Err(Error::new_other(
+                format!("Server root {} isn't a directory", self.root_path).as_str(),
+            ));
+        }
+
+        if self.listen.is_empty() {
+            return Err(Error::new_other("At least one listening address required"));
+        }
+
+        for (host, host_config) in self.hosts.iter() {
+            if let Some(root) = &host_config.root {
+                if !self.before_chroot(root).is_dir() {
+                    return Err(Error::new_other(
+                        format!("Root {} of host {} isn't a directory", root, host).as_str(),
+                    ));
+                }
+            }
+
+            host_config
+                .errors
+                .validate()
+                .map_err(|e| Error::new_other(format!("{} of host {}", e, host).as_str()))?;
+        }
+
+        self.errors
+            .validate()
+            .map_err(|e| Error::new_other(e.as_str()))?;
+
+        if let Some((extension, mime)) = self.mime_types.iter().find(|(_, m)| !m.contains('/')) {
+            return Err(Error::new_other(
+                format!("Wrong mime type {} for {}", mime, extension).as_str(),
+            ));
+        }
+
+        let mut extensions = HashMap::new();
+
+        for extension in self.mime_types.keys() {
+            let normalized = normalize_extension(extension);
+
+            if let Some(other) = extensions.insert(normalized.clone(), extension) {
+                return Err(Error::new_other(
+                    format!(
+                        "Mime type for {} is set twice: {} and {}",
+                        normalized, other, extension
+                    )
+                    .as_str(),
+                ));
+            }
+        }
+
+        if self.max_request_line == 0 {
+            return Err(Error::new_other("Max request line can't be zero"));
+        }
+
+        Ok(())
     }
+
+    /// Configuration sources from lower priority to higher: file(s), enviroment, command line
+    pub fn load_from_config(args: &Args) -> Result<Self> {
+        let files = match &args.config {
+            Some(path) => vec![(path.to_string_lossy().to_string(), true)],
+            None => vec![
+                ("./settings.toml".to_string(), false),
+                ("/etc/rustan/settings.toml".to_string(), false),
+            ],
+        };
+
+        Self::load_from_files(args, files)
+    }
+
+    /// Files are pairs of path and "required" flag
+    fn load_from_files(args: &Args, files: Vec<(String, bool)>) -> Result<Self> {
+        let mut builder = Config::builder();
+
+        for (path, required) in files.iter().cloned() {
+            let source = config::File::new(path.as_str(), FileFormat::Toml).required(required);
+
+            check_source(path.as_str(), source.clone())?;
+            builder = builder.add_source(source);
+        }
+
+        let env = config::Environment::with_prefix("RUSTAN");
+        check_source("enviroment", env.clone())?;
+
+        let mut config = builder
+            .add_source(env)
+            .set_override_option(
+                "host",
+                (!args.listen.is_empty()).then(|| {
+                    args.listen
+                        .iter()
+                        .map(|a| a.to_string())
+                        .collect::<Vec<_>>()
+                }),
+            )
+            .and_then(|b| b.set_override_option("server_root", args.root.clone()))
+            .and_then(|b| {
+                b.set_override_option(
+                    "max_upload_size",
+                    args.max_upload_size.map(|s| s.to_string()),
+                )
+            })
+            .and_then(|b| b.build())
+            .and_then(|c| c.try_deserialize::<Configuration>())
+            .map_err(|e| config_error("command line", e))?;
+
+        config.validate()?;
+
+        config.mime_types = config
+            .mime_types
+            .into_iter()
+            .map(|(extension, mime)| (normalize_extension(extension.as_str()), mime))
+            .collect();
+
+        config.files = files
+            .iter()
+            .map(|(path, _)| Path::new(path))
+            .filter(|path| path.is_file())
+            .filter_map(|path| {
+                let reload_path = config.reload_path(path);
+
+                if reload_path.is_none() {
+                    warn!(
+                        "Configuration file {} is outside of chroot and won't be read on reload",
+                        path.to_string_lossy()
+                    );
+                }
+
+                reload_path
+            })
+            .collect();
+
+        Ok(config)
+    }
+}
+
+fn config_error(source: &str, e: ConfigError) -> Error {
+    Error::new_other(format!("Wrong configuration in {}: {}", source, e).as_str())
+}
+
+/// Every source is deserialized separately, so error will point to broken one
+fn check_source<T>(name: &str, source: T) -> Result<()>
+where
+    T: config::Source + Send + Sync + 'static,
+{
+    Config::builder()
+        .add_source(source)
+        .build()
+        .and_then(|c| c.try_deserialize::<Configuration>())
+        .map(|_| ())
+        .map_err(|e| config_error(name, e))
 }
 
 impl Display for Configuration {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "Listening host: {}\nServer root: {}\nMax upload size: {}",
-            self.host, self.root_path, self.max_upload_size
-        )
+            "Listening host(s): {}\nServer root: {}\nMax upload size: {}\nCGI: {}\nDirectory listing: {}\nCharset: {}\nSymlinks: {}\nHidden files: {}\nMax request line: {}\nTimeouts(request/body/write/shutdown): {}s/{}s/{}s/{}s\nUser/group/chroot: {}/{}/{}",
+            self.listen
+                .iter()
+                .map(|a| a.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            self.root_path,
+            self.max_upload_size,
+            self.cgi,
+            self.directory_listing,
+            self.charset.as_deref().unwrap_or("-"),
+            self.symlink_policy,
+            self.hide_dotfiles
+                .then(|| ".*".to_string())
+                .into_iter()
+                .chain(self.hidden_files.iter().map(|p| p.to_string()))
+                .collect::<Vec<_>>()
+                .join(", "),
+            self.max_request_line,
+            self.request_timeout,
+            self.body_timeout,
+            self.write_timeout,
+            self.shutdown_timeout,
+            self.user.as_deref().unwrap_or("-"),
+            self.group.as_deref().unwrap_or("-"),
+            self.chroot.as_deref().unwrap_or("-")
+        )?;
+
+        for (host, host_config) in self.hosts.iter() {
+            write!(
+                f,
+                "\nHost {}: {} redirect rule(s)",
+                host,
+                host_config.redirects.len()
+            )?;
+
+            if let Some(root) = &host_config.root {
+                write!(f, ", root {}", root)?;
+            }
+        }
+
+        Ok(())
     }
 }
 
 lazy_static! {
-    pub static ref SETTINGS: RwLock<Configuration> =
-        RwLock::new(Configuration::load_from_config().unwrap());
+    /// Filled on start from configuration sources
+    pub static ref SETTINGS: RwLock<Configuration> = RwLock::new(Configuration::default());
+}
+
+/// Files that were read on start are required on reload, so file that is missing
+/// (for example after chroot) won't silently turn into default configuration
+fn reload_files(current: &Configuration) -> Result<Vec<(String, bool)>> {
+    if current.files.is_empty() && is_chrooted() {
+        return Err(Error::new_other(
+            "No configuration file is reachable inside of chroot, reload is refused",
+        ));
+    }
+
+    Ok(current
+        .files
+        .iter()
+        .map(|path| (path.to_string_lossy().to_string(), true))
+        .collect())
+}
+
+/// Loads configuration again and swaps it atomically.
+/// Old configuration stays in place if new one is broken
+pub async fn reload(args: &Args) -> Result<()> {
+    let files = reload_files(&*SETTINGS.read().await)?;
+
+    let config = if files.is_empty() {
+        // Nothing was read on start, so same sources are checked again
+        Configuration::load_from_config(args)?
+    } else {
+        Configuration::load_from_files(args, files)?
+    };
+
+    // Built before locking, so requests are served with old settings meanwhile
+    let table = build_host_table(&config).await?;
+
+    // Same order as in router, settings and hosts are swapped together
+    let mut settings = SETTINGS.write().await;
+    let mut host_table = HOST_TABLE.write().await;
+
+    if settings.listen != config.listen {
+        warn!("Listening addresses can't be changed without restart");
+    }
+
+    if settings.user != config.user
+        || settings.group != config.group
+        || settings.chroot != config.chroot
+    {
+        warn!("User, group and chroot can't be changed without restart");
+    }
+
+    info!("Reloaded config:\n{}", config);
+    *settings = config;
+    *host_table = table;
+
+    Ok(())
+}
+
+// ----------------- Tests section --------------------
+
+/// Default configuration with hosts table taken from TOML
+#[cfg(test)]
+pub fn hosts_test_config(hosts: &str) -> Configuration {
+    let settings = config::Config::builder()
+        .add_source(config::File::from_str(hosts, FileFormat::Toml))
+        .build()
+        .unwrap();
+
+    Configuration {
+        hosts: settings.get("hosts").unwrap(),
+        ..Configuration::default()
+    }
+}
+
+#[test]
+fn validate_existing_root() {
+    let config = Configuration::new(
+        vec!["127.0.0.1:3000".parse().unwrap()],
+        "./example".to_string(),
+        1024,
+    );
+
+    assert_eq!(config.validate(), Ok(()));
+}
+
+#[test]
+fn validate_missing_root() {
+    let config = Configuration::new(
+        vec!["127.0.0.1:3000".parse().unwrap()],
+        "./not-exists".to_string(),
+        1024,
+    );
+
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn load_from_config_file_with_overrides() {
+    let args = Args {
+        config: Some("./settings.toml.example".into()),
+        listen: vec!["127.0.0.1:3001".parse().unwrap()],
+        max_upload_size: Some(1024),
+        ..Args::default()
+    };
+    let config = Configuration::load_from_config(&args).unwrap();
+
+    assert_eq!(config.listen, vec!["127.0.0.1:3001".parse().unwrap()]);
+    assert_eq!(config.root_path, "./example");
+    assert_eq!(config.max_upload_size, 1024);
+}
+
+#[test]
+fn load_from_config_missing_file() {
+    let args = Args {
+        config: Some("./not-exists.toml".into()),
+        ..Args::default()
+    };
+
+    assert!(Configuration::load_from_config(&args).is_err());
+}
+
+#[cfg(test)]
+fn load_test_file(content: &str) -> (tempfile::NamedTempFile, Result<Configuration>) {
+    use std::io::Write;
+
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(content.as_bytes()).unwrap();
+
+    let args = Args {
+        config: Some(file.path().to_path_buf()),
+        ..Args::default()
+    };
+    let result = Configuration::load_from_config(&args);
+
+    (file, result)
+}
+
+#[test]
+fn load_from_config_defaults() {
+    let (_file, result) = load_test_file("");
+    let config = result.unwrap();
+    let default = Configuration::default();
+
+    assert_eq!(config.listen, default.listen);
+    assert_eq!(config.max_upload_size, default.max_upload_size);
+    assert_eq!(config.max_request_line, default.max_request_line);
+}
+
+#[test]
+fn load_from_config_unknown_key() {
+    let (file, result) = load_test_file("hots = \"127.0.0.1:3000\"\n");
+    let message = result.err().unwrap().to_string();
+
+    assert!(message.contains("hots"));
+    assert!(message.contains(file.path().to_string_lossy().as_ref()));
+}
+
+#[test]
+fn load_from_config_wrong_type() {
+    let (file, result) = load_test_file("max_upload_size = \"a lot\"\n");
+    let message = result.err().unwrap().to_string();
+
+    assert!(message.contains("max_upload_size"));
+    assert!(message.contains(file.path().to_string_lossy().as_ref()));
+}
+
+#[test]
+fn load_from_config_wrong_address() {
+    let (_file, result) = load_test_file("host = \"localhost\"\n");
+
+    assert!(result.is_err());
+}
+
+#[test]
+fn load_from_config_unknown_host_key() {
+    let (_file, result) = load_test_file("[hosts.\"example.com\"]\nredirect = []\n");
+
+    assert!(result.is_err());
+}
+
+#[test]
+fn load_from_config_many_listeners() {
+    let (_file, result) =
+        load_test_file("host = [\"0.0.0.0:300\", \"[::]:300\", \"unix:/run/rustan.sock\"]\n");
+
+    assert_eq!(
+        result.unwrap().listen,
+        vec![
+            ListenAddress::Tcp("0.0.0.0:300".parse().unwrap()),
+            ListenAddress::Tcp("[::]:300".parse().unwrap()),
+            ListenAddress::Unix("/run/rustan.sock".into()),
+        ]
+    );
+}
+
+#[test]
+fn validate_root_inside_chroot() {
+    let config = Configuration {
+        chroot: Some("./example".to_string()),
+        ..Configuration::new(
+            vec!["127.0.0.1:3000".parse().unwrap()],
+            "/localhost".to_string(),
+            1024,
+        )
+    };
+
+    assert_eq!(config.validate(), Ok(()));
+
+    let config = Configuration {
+        root_path: "/not-exists".to_string(),
+        ..config
+    };
+
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn validate_missing_chroot() {
+    let config = Configuration {
+        chroot: Some("./not-exists".to_string()),
+        ..Configuration::default()
+    };
+
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn load_from_config_symlink_policy() {
+    let (_file, result) = load_test_file("symlink_policy = \"deny\"\n");
+    assert_eq!(result.unwrap().symlink_policy, SymlinkPolicy::Deny);
+
+    let (_file, result) = load_test_file("symlink_policy = \"sometimes\"\n");
+    assert!(result.is_err());
+}
+
+#[test]
+fn load_from_config_hidden_files() {
+    let (_file, result) = load_test_file("hide_dotfiles = false\nhidden_files = [\".git/\"]\n");
+    let config = result.unwrap();
+
+    assert!(!config.hide_dotfiles);
+    assert_eq!(config.hidden_files.len(), 1);
+
+    let (_file, result) = load_test_file("hidden_files = [\"docs/*.bak\"]\n");
+    assert!(result.is_err());
+}
+
+#[test]
+fn load_from_config_mime_types() {
+    let (_file, result) = load_test_file(
+        "[mime_types]\nmd = \"text/markdown\"\n[hosts.\"localhost\"]\ncharset = \"utf-8\"\nlang = \"en\"\n",
+    );
+    let config = result.unwrap();
+    let host_config = config.host_configuration("localhost").unwrap();
+
+    assert_eq!(
+        config.mime_types.get("md"),
+        Some(&"text/markdown".to_string())
+    );
+    assert_eq!(host_config.charset, Some("utf-8".to_string()));
+    assert_eq!(host_config.lang, Some("en".to_string()));
+
+    let (_file, result) = load_test_file("[mime_types]\nmd = \"markdown\"\n");
+    assert!(result.is_err());
+
+    let (_file, result) = load_test_file("[mime_types]\n\".TXT\" = \"text/x-notes\"\n");
+    assert_eq!(
+        result.unwrap().mime_types.get("txt"),
+        Some(&"text/x-notes".to_string())
+    );
+
+    let (_file, result) =
+        load_test_file("[mime_types]\nmd = \"text/markdown\"\n\".md\" = \"text/plain\"\n");
+    assert!(result.is_err());
+}
+
+#[test]
+fn load_from_config_host_overrides() {
+    let (_file, result) = load_test_file(
+        "[hosts.\"localhost\"]\nroot = \"./example/any\"\nmax_upload_size = 10\ncgi = false\ndirectory_listing = \"always\"\n",
+    );
+    let config = result.unwrap();
+    let host_config = config.host_configuration("localhost").unwrap();
+
+    assert_eq!(host_config.root, Some("./example/any".to_string()));
+    assert_eq!(host_config.max_upload_size, Some(10));
+    assert_eq!(host_config.cgi, Some(false));
+    assert_eq!(
+        host_config.directory_listing,
+        Some(DirectoryListing::Always)
+    );
+
+    let (_file, result) = load_test_file("[hosts.\"localhost\"]\nroot = \"./not-exists\"\n");
+    assert!(result.is_err());
+}
+
+#[test]
+fn load_from_config_records_files() {
+    let (file, result) = load_test_file("server_root = \"./example\"\n");
+    let config = result.unwrap();
+
+    assert_eq!(config.files, vec![file.path().canonicalize().unwrap()]);
+    assert_eq!(
+        reload_files(&config),
+        Ok(vec![(
+            file.path()
+                .canonicalize()
+                .unwrap()
+                .to_string_lossy()
+                .to_string(),
+            true
+        )])
+    );
+}
+
+#[test]
+fn reload_path_inside_chroot() {
+    let dir = tempfile::tempdir().unwrap();
+    let chroot = dir.path().join("chroot");
+    let inside = chroot.join("etc/settings.toml");
+    let outside = dir.path().join("settings.toml");
+
+    std::fs::create_dir_all(chroot.join("etc")).unwrap();
+    std::fs::write(&inside, "").unwrap();
+    std::fs::write(&outside, "").unwrap();
+
+    let config = Configuration {
+        chroot: Some(chroot.to_string_lossy().to_string()),
+        ..Configuration::default()
+    };
+
+    assert_eq!(
+        config.reload_path(&inside),
+        Some(PathBuf::from("/etc/settings.toml"))
+    );
+    assert_eq!(config.reload_path(&outside), None);
 }
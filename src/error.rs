@@ -1,22 +1,26 @@
 use std::fmt;
+use std::io;
 use std::result;
 
 pub type Result<T> = result::Result<T, Error>;
 
-#[derive(Clone, Eq, PartialEq, Debug)]
+/// Kind decides status code and public message of response, see `Response::from_error`
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
 pub enum ErrorKind {
+    #[default]
     Io,
-    RequestError,
+    /// Client sent something wrong, message is safe to show
+    BadRequest,
+    NotFound,
+    Forbidden,
+    /// Client or connection was too slow, message is safe to show
+    Timeout,
+    /// CGI script failed or answered with garbage
+    Upstream,
     Other,
     Unexpected,
 }
 
-impl Default for ErrorKind {
-    fn default() -> ErrorKind {
-        ErrorKind::Io
-    }
-}
-
 #[derive(Debug, Default, PartialEq)]
 pub struct Error {
     kind: ErrorKind,
@@ -35,8 +39,24 @@ impl Error {
         Error::new(ErrorKind::Io, msg)
     }
 
-    pub fn new_request_error(msg: &str) -> Error {
-        Error::new(ErrorKind::RequestError, msg)
+    pub fn new_bad_request(msg: &str) -> Error {
+        Error::new(ErrorKind::BadRequest, msg)
+    }
+
+    pub fn new_not_found(msg: &str) -> Error {
+        Error::new(ErrorKind::NotFound, msg)
+    }
+
+    pub fn new_forbidden(msg: &str) -> Error {
+        Error::new(ErrorKind::Forbidden, msg)
+    }
+
+    pub fn new_timeout(msg: &str) -> Error {
+        Error::new(ErrorKind::Timeout, msg)
+    }
+
+    pub fn new_upstream(msg: &str) -> Error {
+        Error::new(ErrorKind::Upstream, msg)
     }
 
     pub fn new_other(msg: &str) -> Error {
@@ -46,22 +66,73 @@ impl Error {
     pub fn new_unexpected(msg: &str) -> Error {
         Error::new(ErrorKind::Unexpected, msg)
     }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// Detailed message without kind. Usually only for logs
+    pub fn message(&self) -> &str {
+        self.msg.as_str()
+    }
+}
+
+/// Keeps difference between missing file, denied access and real failure
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        let kind = match e.kind() {
+            io::ErrorKind::NotFound => ErrorKind::NotFound,
+            io::ErrorKind::PermissionDenied => ErrorKind::Forbidden,
+            io::ErrorKind::TimedOut => ErrorKind::Timeout,
+            _ => ErrorKind::Io,
+        };
+
+        Error::new(kind, e.to_string().as_str())
+    }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self.kind {
             ErrorKind::Io => write!(f, "Io error: {}", self.msg),
-            ErrorKind::RequestError => write!(f, "Request error: {}", self.msg),
+            ErrorKind::BadRequest => write!(f, "Bad request: {}", self.msg),
+            ErrorKind::NotFound => write!(f, "Not found: {}", self.msg),
+            ErrorKind::Forbidden => write!(f, "Forbidden: {}", self.msg),
+            ErrorKind::Timeout => write!(f, "Timeout: {}", self.msg),
+            ErrorKind::Upstream => write!(f, "Upstream error: {}", self.msg),
             ErrorKind::Other => write!(f, "Other error: {}", self.msg),
             ErrorKind::Unexpected => write!(f, "Unexpected error: {}", self.msg),
         }
     }
 }
 
+/// Converts io::Error keeping its kind(missing file, denied access, timeout)
 #[macro_export]
 macro_rules! io_err {
     ($x: expr ) => {
-        $x.map_err(|e| Error::new_io(e.to_string().as_str()))
+        $x.map_err(Error::from)
     };
 }
+
+// ----------------- Tests section --------------------
+
+#[test]
+fn from_io_error_keeps_kind() {
+    let missing = io::Error::new(io::ErrorKind::NotFound, "missing");
+    let denied = io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+    let broken = io::Error::new(io::ErrorKind::BrokenPipe, "broken");
+
+    assert_eq!(Error::from(missing), Error::new_not_found("missing"));
+    assert_eq!(Error::from(denied), Error::new_forbidden("denied"));
+    assert_eq!(Error::from(broken), Error::new_io("broken"));
+}
+
+#[test]
+fn io_err_keeps_kind() {
+    let result = io_err!(std::fs::metadata("./not-exists"));
+
+    assert_eq!(
+        result.map_err(|e| e.kind()).err(),
+        Some(ErrorKind::NotFound)
+    );
+}
@@ -0,0 +1,461 @@
+use crate::error::{Error, Result};
+
+use log::{debug, warn};
+use serde::{Deserialize, Deserializer};
+use socket2::{Domain, Socket, Type};
+use std::env;
+use std::fmt;
+use std::future::poll_fn;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+const UNIX_PREFIX: &str = "unix:";
+
+const BACKLOG: i32 = 1024;
+
+/// First descriptor passed by systemd, after stdin/stdout/stderr
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Enviroment of socket activation. It belongs to server only, so it's removed for children
+pub const ACTIVATION_VARIABLES: [&str; 3] = ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"];
+
+/// Listening address from configuration: "0.0.0.0:300", "[::]:300" or "unix:/run/rustan.sock"
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.strip_prefix(UNIX_PREFIX) {
+            Some("") => Err(format!("Empty unix socket path in {}", s)),
+            Some(path) => Ok(ListenAddress::Unix(PathBuf::from(path))),
+            None => s
+                .parse::<SocketAddr>()
+                .map(ListenAddress::Tcp)
+                .map_err(|e| format!("Wrong listening address {}: {}", s, e)),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddress::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddress::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.to_string_lossy()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenAddress {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let address = String::deserialize(deserializer)?;
+
+        address.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Allows both `host = "..."` and `host = ["...", "..."]`
+pub fn one_or_many<'de, D>(deserializer: D) -> std::result::Result<Vec<ListenAddress>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(ListenAddress),
+        Many(Vec<ListenAddress>),
+    }
+
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(address) => Ok(vec![address]),
+        OneOrMany::Many(addresses) => Ok(addresses),
+    }
+}
+
+/// Accepted client connection
+pub enum Accepted {
+    Tcp {
+        stream: TcpStream,
+        remote_addr: SocketAddr,
+        local_addr: SocketAddr,
+    },
+    Unix {
+        stream: UnixStream,
+    },
+}
+
+impl Accepted {
+    /// Client description for logs
+    pub fn peer(&self) -> String {
+        match self {
+            Accepted::Tcp { remote_addr, .. } => remote_addr.to_string(),
+            Accepted::Unix { .. } => "unix client".to_string(),
+        }
+    }
+}
+
+/// IPv6 sockets are bound as IPv6 only, so "0.0.0.0:300" and "[::]:300" can be used together
+fn bind_tcp(addr: &SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, None)?;
+
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&(*addr).into())?;
+    socket.listen(BACKLOG)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+/// Socket file left from previous run blocks binding. It's removed only if nobody
+/// listens it, other files and sockets of running servers are left untouched
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "file exists and isn't a socket",
+        ));
+    }
+
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "socket is used by other process",
+        )),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            debug!("Removing stale socket {}", path.to_string_lossy());
+            std::fs::remove_file(path)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener, SocketAddr),
+    Unix(UnixListener, PathBuf),
+    /// Unix socket passed by systemd, its file belongs to systemd and isn't removed
+    ActivatedUnix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub fn bind(address: &ListenAddress) -> Result<Self> {
+        let result = match address {
+            ListenAddress::Tcp(addr) => bind_tcp(addr).and_then(|listener| {
+                let local_addr = listener.local_addr()?;
+
+                Ok(Listener::Tcp(listener, local_addr))
+            }),
+            ListenAddress::Unix(path) => remove_stale_socket(path)
+                .and_then(|_| UnixListener::bind(path))
+                .map(|listener| Listener::Unix(listener, path.clone())),
+        };
+
+        result.map_err(|e| Error::new_io(format!("Can't listen {}: {}", address, e).as_str()))
+    }
+
+    /// Takes already listening socket that was passed to process
+    fn from_fd(fd: RawFd) -> Result<Self> {
+        // SAFETY: descriptor was passed for this process and nothing else owns it
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+
+        Listener::from_socket(socket)
+            .map_err(|e| Error::new_io(format!("Can't use passed socket {}: {}", fd, e).as_str()))
+    }
+
+    fn from_socket(socket: Socket) -> io::Result<Self> {
+        if socket.r#type()? != Type::STREAM {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a stream socket",
+            ));
+        }
+
+        // CGI scripts shouldn't inherit listening sockets
+        socket.set_cloexec(true)?;
+        socket.set_nonblocking(true)?;
+
+        let address = socket.local_addr()?;
+
+        if let Some(local_addr) = address.as_socket() {
+            return Ok(Listener::Tcp(
+                TcpListener::from_std(socket.into())?,
+                local_addr,
+            ));
+        }
+
+        if Domain::from(address.family() as i32) != Domain::UNIX {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unsupported address family",
+            ));
+        }
+
+        let listener = std::os::unix::net::UnixListener::from(socket);
+        let path = listener
+            .local_addr()?
+            .as_pathname()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+
+        Ok(Listener::ActivatedUnix(
+            UnixListener::from_std(listener)?,
+            path,
+        ))
+    }
+
+    pub fn address(&self) -> ListenAddress {
+        match self {
+            Listener::Tcp(_, addr) => ListenAddress::Tcp(*addr),
+            Listener::Unix(_, path) | Listener::ActivatedUnix(_, path) => {
+                ListenAddress::Unix(path.clone())
+            }
+        }
+    }
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<Accepted>> {
+        match self {
+            Listener::Tcp(listener, local_addr) => {
+                listener
+                    .poll_accept(cx)
+                    .map_ok(|(stream, remote_addr)| Accepted::Tcp {
+                        stream,
+                        remote_addr,
+                        local_addr: *local_addr,
+                    })
+            }
+            Listener::Unix(listener, _) | Listener::ActivatedUnix(listener, _) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, _)| Accepted::Unix { stream }),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            if let Err(e) = std::fs::remove_file(&path) {
+                warn!("Can't remove socket {}: {}", path.to_string_lossy(), e);
+            }
+        }
+    }
+}
+
+/// Count of sockets passed by systemd(sd_listen_fds(3) rules).
+/// Sockets for another process(LISTEN_PID differs) are ignored
+fn listen_fds_count(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> Result<usize> {
+    let (listen_pid, listen_fds) = match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(listen_fds)) => (listen_pid, listen_fds),
+        _ => return Ok(0),
+    };
+
+    let listen_pid = listen_pid.parse::<u32>().map_err(|e| {
+        Error::new_other(format!("Wrong LISTEN_PID {}: {}", listen_pid, e).as_str())
+    })?;
+
+    if listen_pid != pid {
+        return Ok(0);
+    }
+
+    listen_fds
+        .parse::<usize>()
+        .map_err(|e| Error::new_other(format!("Wrong LISTEN_FDS {}: {}", listen_fds, e).as_str()))
+}
+
+/// Sockets passed with systemd socket activation. Empty when there're no ones,
+/// so configured addresses should be bound
+pub fn activated_listeners() -> Result<Vec<Listener>> {
+    let count = listen_fds_count(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    )?;
+
+    (0..count)
+        .map(|index| Listener::from_fd(LISTEN_FDS_START + index as RawFd))
+        .collect()
+}
+
+/// Waits for connection on any of listeners. Returns index of listener that accepted it.
+/// Listeners are polled starting from first, so busy listener can't starve others
+/// when caller passes next index after accepted one
+pub async fn accept_any(listeners: &[Listener], first: usize) -> (usize, Result<Accepted>) {
+    poll_fn(|cx| {
+        for offset in 0..listeners.len() {
+            let index = (first + offset) % listeners.len();
+
+            if let Poll::Ready(result) = listeners[index].poll_accept(cx) {
+                return Poll::Ready((index, io_err!(result)));
+            }
+        }
+
+        Poll::Pending
+    })
+    .await
+}
+
+// ----------------- Tests section --------------------
+
+#[test]
+fn parse_listen_address() {
+    assert_eq!(
+        "127.0.0.1:300".parse(),
+        Ok(ListenAddress::Tcp("127.0.0.1:300".parse().unwrap()))
+    );
+    assert_eq!(
+        "[::]:300".parse(),
+        Ok(ListenAddress::Tcp("[::]:300".parse().unwrap()))
+    );
+    assert_eq!(
+        "unix:/run/rustan.sock".parse(),
+        Ok(ListenAddress::Unix(PathBuf::from("/run/rustan.sock")))
+    );
+    assert!("unix:".parse::<ListenAddress>().is_err());
+    assert!("localhost".parse::<ListenAddress>().is_err());
+}
+
+#[test]
+fn display_listen_address() {
+    let unix = ListenAddress::Unix(PathBuf::from("/run/rustan.sock"));
+    let tcp = ListenAddress::Tcp("[::1]:300".parse().unwrap());
+
+    assert_eq!(unix.to_string(), "unix:/run/rustan.sock");
+    assert_eq!(tcp.to_string(), "[::1]:300");
+}
+
+#[tokio::test]
+async fn accept_any_unix_and_tcp() {
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("rustan.sock");
+
+    let listeners = vec![
+        Listener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap(),
+        Listener::bind(&ListenAddress::Unix(socket.clone())).unwrap(),
+    ];
+
+    let _client = UnixStream::connect(&socket).await.unwrap();
+    let (index, accepted) = accept_any(&listeners, 0).await;
+
+    assert_eq!(index, 1);
+    assert!(matches!(accepted, Ok(Accepted::Unix { .. })));
+
+    drop(listeners);
+    assert!(!socket.exists());
+}
+
+#[tokio::test]
+async fn accept_any_starts_from_first() {
+    let listeners = vec![
+        Listener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap(),
+        Listener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap(),
+    ];
+    let mut clients = Vec::new();
+
+    for listener in &listeners {
+        if let ListenAddress::Tcp(addr) = listener.address() {
+            clients.push(TcpStream::connect(addr).await.unwrap());
+        }
+    }
+
+    // Both listeners are ready, so the one polled first wins
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert_eq!(accept_any(&listeners, 1).await.0, 1);
+    assert_eq!(accept_any(&listeners, 2).await.0, 0);
+}
+
+#[tokio::test]
+async fn bind_unix_over_existing_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let stale = dir.path().join("stale.sock");
+    let used = dir.path().join("used.sock");
+    let regular = dir.path().join("passwd");
+
+    drop(std::os::unix::net::UnixListener::bind(&stale).unwrap());
+    let _running = std::os::unix::net::UnixListener::bind(&used).unwrap();
+    std::fs::write(&regular, "root:x:0:0").unwrap();
+
+    assert!(Listener::bind(&ListenAddress::Unix(stale)).is_ok());
+    assert!(Listener::bind(&ListenAddress::Unix(used.clone())).is_err());
+    assert!(Listener::bind(&ListenAddress::Unix(regular.clone())).is_err());
+    assert!(used.exists());
+    assert_eq!(std::fs::read(&regular).unwrap(), b"root:x:0:0");
+}
+
+#[tokio::test]
+async fn bind_ipv4_and_ipv6_on_same_port() {
+    let ipv4 = Listener::bind(&"0.0.0.0:0".parse().unwrap()).unwrap();
+    let port = match ipv4.address() {
+        ListenAddress::Tcp(addr) => addr.port(),
+        _ => unreachable!(),
+    };
+
+    // Host can be without IPv6 support at all
+    if let Ok(ipv6) = bind_tcp(&SocketAddr::from(([0u16; 8], 0))) {
+        drop(ipv6);
+
+        let ipv6 = Listener::bind(&ListenAddress::Tcp(SocketAddr::from(([0u16; 8], port))));
+        assert!(ipv6.is_ok());
+    }
+}
+
+#[test]
+fn listen_fds_count_for_own_pid() {
+    assert_eq!(listen_fds_count(Some("42"), Some("2"), 42), Ok(2));
+    assert_eq!(listen_fds_count(Some("43"), Some("2"), 42), Ok(0));
+    assert_eq!(listen_fds_count(None, Some("2"), 42), Ok(0));
+    assert_eq!(listen_fds_count(Some("42"), None, 42), Ok(0));
+    assert!(listen_fds_count(Some("42"), Some("many"), 42).is_err());
+    assert!(listen_fds_count(Some("pid"), Some("2"), 42).is_err());
+}
+
+#[tokio::test]
+async fn listener_from_passed_sockets() {
+    use std::os::unix::io::IntoRawFd;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("activated.sock");
+
+    let tcp = bind_tcp(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let tcp_addr = tcp.local_addr().unwrap();
+    let tcp_fd = tcp.into_std().unwrap().into_raw_fd();
+    let unix_fd = std::os::unix::net::UnixListener::bind(&path)
+        .unwrap()
+        .into_raw_fd();
+
+    let tcp = Listener::from_fd(tcp_fd).unwrap();
+    let unix = Listener::from_fd(unix_fd).unwrap();
+
+    assert_eq!(tcp.address(), ListenAddress::Tcp(tcp_addr));
+    assert_eq!(unix.address(), ListenAddress::Unix(path.clone()));
+
+    let _client = UnixStream::connect(&path).await.unwrap();
+    let (index, accepted) = accept_any(&[tcp, unix], 0).await;
+
+    assert_eq!(index, 1);
+    assert!(matches!(accepted, Ok(Accepted::Unix { .. })));
+    // Socket file is left for systemd
+    assert!(path.exists());
+}
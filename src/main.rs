@@ -1,46 +1,246 @@
 #[macro_use]
 pub mod error;
+pub mod cli;
 pub mod configuration;
+pub mod listener;
 pub mod mime;
 pub mod pipe;
+pub mod privileges;
 pub mod protocol;
 
-use configuration::{Configuration, SETTINGS};
+use clap::Parser;
+use cli::Args;
+use configuration::{reload, Configuration, SETTINGS};
 use error::{Error, Result};
-use log::{error, info};
-use pipe::{connection::Connection, handler};
-use tokio::net::TcpListener;
+use listener::{accept_any, activated_listeners, Accepted, ListenAddress, Listener};
+use log::{error, info, warn};
+use pipe::{
+    cgi::SCRIPT_KILL_DELAY,
+    connection::{Connection, Timeouts},
+    handler,
+    vhost::refresh_host_table,
+};
+use privileges::drop_privileges;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout};
+
+/// Pause after failed accept, so server won't spin while out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Resolves on SIGTERM or SIGINT
+async fn shutdown_signal() -> Result<()> {
+    let mut terminate = io_err!(signal(SignalKind::terminate()))?;
+    let mut interrupt = io_err!(signal(SignalKind::interrupt()))?;
+
+    tokio::select! {
+        _ = terminate.recv() => info!("SIGTERM received"),
+        _ = interrupt.recv() => info!("SIGINT received"),
+    }
+
+    Ok(())
+}
+
+/// Reloads configuration on every SIGHUP
+async fn reload_on_hangup(args: Args) -> Result<()> {
+    let mut hangup = io_err!(signal(SignalKind::hangup()))?;
+
+    while hangup.recv().await.is_some() {
+        info!("SIGHUP received, reloading configuration");
+
+        if let Err(e) = reload(&args).await {
+            error!("Configuration wasn't reloaded: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Waits for active connections, remaining ones will be aborted after grace period.
+/// Aborted handlers drop their CGI processes, so scripts will be killed too
+async fn drain_connections(mut tasks: JoinSet<()>, grace_period: Duration) {
+    let active = tasks.len();
+    info!(
+        "Waiting for {} active connection(s) up to {:?}",
+        active, grace_period
+    );
+
+    let drained = timeout(grace_period, async {
+        while tasks.join_next().await.is_some() {}
+    })
+    .await;
+
+    let aborted = tasks.len();
+
+    if drained.is_err() {
+        warn!("Grace period is over, aborting {} connection(s)", aborted);
+        tasks.shutdown().await;
+
+        // Scripts of aborted connections get SIGTERM first, SIGKILL is sent later
+        sleep(SCRIPT_KILL_DELAY).await;
+    }
+
+    info!(
+        "Server stopped: {} connection(s) finished, {} aborted",
+        active - aborted,
+        aborted
+    );
+}
+
+async fn serve<R, W>(
+    mut connection: Connection<R, W>,
+    remote_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    peer: String,
+    address: ListenAddress,
+) where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match handler(&mut connection, remote_addr, local_addr).await {
+        Ok(r) => info!("Request on {} processed successfully: {}", address, r),
+        Err(e) => error!("Request from {} on {} produced issue: {}", peer, address, e),
+    }
+}
 
 async fn create_server(config: Configuration) -> Result<()> {
-    let listener = io_err!(TcpListener::bind(config.host).await)?;
+    let mut listeners = activated_listeners()?;
+
+    if listeners.is_empty() {
+        for address in config.listen.iter() {
+            listeners.push(Listener::bind(address)?);
+        }
+    } else {
+        info!("Using {} socket(s) passed by systemd", listeners.len());
+    }
+
+    for listener in listeners.iter() {
+        info!("Listening on {}", listener.address());
+    }
+
+    // Listeners are bound already, so root isn't needed anymore
+    drop_privileges(&config)?;
+
+    // After chroot, cause host directories are inside of it
+    refresh_host_table(&config).await?;
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    let mut tasks = JoinSet::new();
+    let mut first_listener = 0;
 
     loop {
-        let (socket, ip) = io_err!(listener.accept().await)?;
-
-        info!("Handling connection for {}", ip.to_string());
-
-        tokio::spawn(async move {
-            match handler(&mut Connection::new(socket)).await {
-                Ok(r) => info!("Request processed successfully: {}", r.to_string()),
-                Err(e) => error!(
-                    "Request from {} produced issue: {}",
-                    ip.to_string(),
-                    e.to_string()
-                ),
+        let (index, accepted) = tokio::select! {
+            accepted = accept_any(&listeners, first_listener) => accepted,
+            // Finished handlers should be removed from set
+            Some(_) = tasks.join_next() => continue,
+            signal = &mut shutdown => {
+                signal?;
+                break;
             }
-        });
+        };
+
+        first_listener = index + 1;
+
+        // Accept errors(EMFILE, ECONNABORTED) are temporary, server keeps working
+        let accepted = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!(
+                    "Can't accept connection on {}: {}",
+                    listeners[index].address(),
+                    e
+                );
+                sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let address = listeners[index].address();
+        let peer = accepted.peer();
+
+        info!("Handling connection for {} on {}", peer, address);
+
+        // Settings can be reloaded, so they're taken for every connection
+        let timeouts = Timeouts::from(&*SETTINGS.read().await);
+
+        match accepted {
+            Accepted::Tcp {
+                stream,
+                remote_addr,
+                local_addr,
+            } => tasks.spawn(serve(
+                Connection::from_tcp(stream, timeouts),
+                Some(remote_addr),
+                Some(local_addr),
+                peer,
+                address,
+            )),
+            Accepted::Unix { stream } => tasks.spawn(serve(
+                Connection::from_unix(stream, timeouts),
+                None,
+                None,
+                peer,
+                address,
+            )),
+        };
     }
+
+    // Stop accepting new connections
+    drop(listeners);
+
+    let grace_period = Duration::from_secs(SETTINGS.read().await.shutdown_timeout);
+    drain_connections(tasks, grace_period).await;
+
+    Ok(())
+}
+
+/// Checks configuration for --check-config
+fn check_config(args: &Args) -> Result<()> {
+    let config = Configuration::load_from_config(args)?;
+
+    println!("{}", config);
+
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args = Args::parse();
     env_logger::init();
+
+    if args.check_config {
+        if let Err(e) = check_config(&args) {
+            eprintln!("Configuration is broken: {}", e);
+            std::process::exit(1);
+        }
+
+        return Ok(());
+    }
+
     info!("Starting application!");
-    let config = SETTINGS.read().await.to_owned();
+    let config = match Configuration::load_from_config(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Can't load configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
     info!("Loaded config:\n{}", config);
+    *SETTINGS.write().await = config.clone();
+
+    tokio::spawn(async move {
+        if let Err(e) = reload_on_hangup(args).await {
+            error!("Can't handle SIGHUP: {}", e);
+        }
+    });
 
     if let Err(e) = create_server(config).await {
-        error!("Server error: {}", e.to_string())
+        error!("Server error: {}", e);
+        std::process::exit(1);
     }
 
     Ok(())
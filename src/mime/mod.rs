@@ -1,7 +1,110 @@
 use mime_guess;
+use std::collections::HashMap;
+use std::path::Path;
+
+pub const GEMINI_MIME: &str = "text/gemini";
+
+/// Types that mime_guess doesn't know(or knows not in every version)
+const BUILTIN_TYPES: [(&str, &str); 2] = [("gmi", GEMINI_MIME), ("gemini", GEMINI_MIME)];
 
 pub fn filename_to_mime(filename: String) -> String {
     let mime = mime_guess::from_path(filename.as_str());
 
     mime.first_or_octet_stream().to_string()
 }
+
+/// Extensions in configuration can be written with or without dot and in any case
+pub fn normalize_extension(extension: &str) -> String {
+    extension.trim_start_matches('.').to_lowercase()
+}
+
+/// Looks into configured types first, then into builtin ones and only then guesses.
+/// Configured extensions should be normalized already
+pub fn path_to_mime(path: &Path, types: &HashMap<String, String>) -> String {
+    let extension = match path.extension() {
+        Some(extension) => normalize_extension(extension.to_string_lossy().as_ref()),
+        None => return filename_to_mime(path.to_string_lossy().to_string()),
+    };
+
+    types
+        .get(&extension)
+        .cloned()
+        .or_else(|| {
+            BUILTIN_TYPES
+                .iter()
+                .find(|(ext, _)| *ext == extension)
+                .map(|(_, mime)| mime.to_string())
+        })
+        .unwrap_or_else(|| filename_to_mime(path.to_string_lossy().to_string()))
+}
+
+/// Appends charset and lang to text types, if they aren't set already
+pub fn with_parameters(mime: String, charset: Option<&str>, lang: Option<&str>) -> String {
+    if !mime.starts_with("text/") {
+        return mime;
+    }
+
+    [("charset", charset), ("lang", lang)]
+        .into_iter()
+        .filter_map(|(name, value)| value.map(|v| (name, v)))
+        .filter(|(name, _)| !mime.contains(format!("{}=", name).as_str()))
+        .fold(mime.clone(), |mime, (name, value)| {
+            format!("{}; {}={}", mime, name, value)
+        })
+}
+
+// ----------------- Tests section --------------------
+
+#[test]
+fn path_to_mime_builtin_and_guessed() {
+    let types = HashMap::new();
+
+    assert_eq!(path_to_mime(Path::new("index.gmi"), &types), "text/gemini");
+    assert_eq!(
+        path_to_mime(Path::new("page.GEMINI"), &types),
+        "text/gemini"
+    );
+    assert_eq!(path_to_mime(Path::new("notes.txt"), &types), "text/plain");
+    assert_eq!(
+        path_to_mime(Path::new("LICENSE"), &types),
+        "application/octet-stream"
+    );
+}
+
+#[test]
+fn path_to_mime_configured() {
+    let types = HashMap::from([
+        ("md".to_string(), "text/markdown".to_string()),
+        ("gmi".to_string(), "text/plain".to_string()),
+    ]);
+
+    assert_eq!(
+        path_to_mime(Path::new("readme.md"), &types),
+        "text/markdown"
+    );
+    assert_eq!(path_to_mime(Path::new("index.gmi"), &types), "text/plain");
+}
+
+#[test]
+fn with_parameters_for_text_only() {
+    assert_eq!(
+        with_parameters("text/gemini".to_string(), Some("utf-8"), Some("en")),
+        "text/gemini; charset=utf-8; lang=en"
+    );
+    assert_eq!(
+        with_parameters("text/plain".to_string(), None, Some("fr")),
+        "text/plain; lang=fr"
+    );
+    assert_eq!(
+        with_parameters(
+            "text/plain; charset=koi8-r".to_string(),
+            Some("utf-8"),
+            None
+        ),
+        "text/plain; charset=koi8-r"
+    );
+    assert_eq!(
+        with_parameters("image/png".to_string(), Some("utf-8"), Some("en")),
+        "image/png"
+    );
+}
@@ -0,0 +1,414 @@
+use crate::error::{Error, Result};
+use crate::listener::ACTIVATION_VARIABLES;
+use crate::protocol::request::Request;
+use crate::protocol::response::{Body, Response, StatusCode};
+use bytes::Bytes;
+use is_executable::IsExecutable;
+use log::debug;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{ChildStdin, Command};
+use tokio::runtime::Handle;
+use tokio::time::sleep;
+
+const EMPTY_OUTPUT: &str = "Script produced no output";
+
+/// Time between SIGTERM and SIGKILL for scripts of aborted requests
+pub const SCRIPT_KILL_DELAY: Duration = Duration::from_secs(1);
+
+/// Executable that serves request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Script {
+    pub path: PathBuf,
+    /// Locator of script itself
+    pub name: String,
+    /// Rest of locator after script name
+    pub path_info: String,
+}
+
+impl Script {
+    pub fn new(path: PathBuf, name: String, path_info: String) -> Self {
+        Self {
+            path,
+            name,
+            path_info,
+        }
+    }
+}
+
+/// Script runs in own process group, so processes started by it are stopped
+/// together with it when request is aborted
+struct ScriptGroup {
+    pid: Option<libc::pid_t>,
+}
+
+impl ScriptGroup {
+    fn new(pid: Option<u32>) -> Self {
+        Self {
+            pid: pid.and_then(|pid| libc::pid_t::try_from(pid).ok()),
+        }
+    }
+
+    /// Script exited by itself, nothing to stop
+    fn finished(&mut self) {
+        self.pid = None;
+    }
+}
+
+fn signal_group(pid: libc::pid_t, signal: libc::c_int) {
+    // SAFETY: simple syscall without pointers
+    unsafe { libc::kill(-pid, signal) };
+}
+
+impl Drop for ScriptGroup {
+    fn drop(&mut self) {
+        let Some(pid) = self.pid else {
+            return;
+        };
+
+        debug!("Stopping script group {}", pid);
+        signal_group(pid, libc::SIGTERM);
+
+        match Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(async move {
+                    sleep(SCRIPT_KILL_DELAY).await;
+                    signal_group(pid, libc::SIGKILL);
+                });
+            }
+            Err(_) => signal_group(pid, libc::SIGKILL),
+        }
+    }
+}
+
+/// Walks locator segments until executable found, so "/app.cgi/users/42" will be served by "app.cgi"
+pub fn find_script(host_root: &Path, locator: &str) -> Option<Script> {
+    let mut path = host_root.to_path_buf();
+    let mut name_len = 0;
+
+    for segment in locator[1..].split('/') {
+        if segment.is_empty() {
+            return None;
+        }
+
+        path.push(segment);
+        name_len += segment.len() + 1;
+
+        if path.is_file() {
+            return path.is_executable().then(|| {
+                Script::new(
+                    path,
+                    locator[..name_len].to_string(),
+                    locator[name_len..].to_string(),
+                )
+            });
+        }
+
+        if !path.is_dir() {
+            return None;
+        }
+    }
+
+    None
+}
+
+const SERVER_SOFTWARE: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Request description for script in RFC 3875 manner
+fn cgi_environment(script: &Script, request: &Request) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+        ("SERVER_SOFTWARE", SERVER_SOFTWARE.to_string()),
+        ("SERVER_PROTOCOL", "SPARTAN".to_string()),
+        ("SERVER_NAME", request.host.clone()),
+        ("SCRIPT_NAME", script.name.clone()),
+        ("PATH_INFO", script.path_info.clone()),
+        ("QUERY_STRING", request.query.clone().unwrap_or_default()),
+        ("CONTENT_LENGTH", request.data_len.to_string()),
+    ];
+
+    if let Some(addr) = request.local_addr {
+        env.push(("SERVER_PORT", addr.port().to_string()));
+    }
+
+    if let Some(addr) = request.remote_addr {
+        env.push(("REMOTE_ADDR", addr.ip().to_string()));
+        env.push(("REMOTE_PORT", addr.port().to_string()));
+    }
+
+    env
+}
+
+async fn write_input(mut stdin: ChildStdin, data: Bytes) -> Result<()> {
+    io_err!(stdin.write_all(&data[..]).await)?;
+    io_err!(stdin.flush().await)?;
+
+    // Closing stdin, so script will get EOF
+    drop(stdin);
+
+    Ok(())
+}
+
+/// Script output is "status-code SPACE status-line CRLF" and optional body
+async fn read_output<R>(output: R) -> Result<Response>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut reader = BufReader::new(output);
+
+    let mut status_code_b: Vec<u8> = Vec::with_capacity(2);
+    let mut status_line: Vec<u8> = Vec::new();
+    let mut buf: Vec<u8> = Vec::new();
+
+    io_err!(reader.read_until(32u8, &mut status_code_b).await)?;
+
+    // align char numbers
+    let status_code_num = status_code_b
+        .first()
+        .ok_or_else(|| Error::new_upstream(EMPTY_OUTPUT))?
+        .wrapping_sub(48);
+    let status_code = StatusCode::from_number(status_code_num);
+
+    io_err!(reader.read_until(10u8, &mut status_line).await)?;
+
+    let status_line_str = String::from_utf8(status_line)
+        .map(|s| s.trim_end().to_string())
+        .map_err(|e| Error::new_upstream(format!("Wrong script status line: {}", e).as_str()))?;
+
+    io_err!(reader.read_to_end(&mut buf).await)?;
+
+    Ok(Response::new(
+        status_code,
+        status_line_str,
+        Some(Body::Buffered(Bytes::from(buf))),
+    ))
+}
+
+pub async fn process_cgi(script: Script, request: Request) -> Result<Response> {
+    debug!(
+        "Executed cgi: {} with path info {:?}",
+        script.path.to_string_lossy(),
+        script.path_info
+    );
+
+    let env = cgi_environment(&script, &request);
+    let path = script.path;
+    let data = request.data.unwrap_or_default();
+
+    let mut command = std::process::Command::new(path.clone());
+
+    // Scripts shouldn't think that sockets were passed to them
+    for name in ACTIVATION_VARIABLES {
+        command.env_remove(name);
+    }
+
+    command
+        .envs(env)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .current_dir(path.parent().unwrap().as_os_str())
+        .process_group(0);
+
+    let mut child = Command::from(command).spawn().map_err(|e| {
+        Error::new_upstream(format!("Can't run {}: {}", path.to_string_lossy(), e).as_str())
+    })?;
+    let mut group = ScriptGroup::new(child.id());
+
+    let stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| Error::new_unexpected("Script stdin lost"))?;
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| Error::new_unexpected("Script stdout lost"))?;
+
+    // Input is written while output is read, so big output won't lock script
+    let (written, response) = tokio::join!(write_input(stdin, data), read_output(stdout));
+
+    if let Err(e) = written {
+        debug!("Script didn't read whole input: {}", e);
+    }
+
+    let status = io_err!(child.wait().await)?;
+    group.finished();
+    debug!("Script {} finished with {}", path.to_string_lossy(), status);
+
+    response
+}
+
+// ----------------- Tests section --------------------
+
+#[tokio::test]
+async fn read_output_with_body() {
+    let output = &b"2 text/gemini\r\n# Hello\r\n"[..];
+    let expect = Response::new(
+        StatusCode::Success,
+        "text/gemini".to_string(),
+        Some(Body::Buffered(Bytes::from(&b"# Hello\r\n"[..]))),
+    );
+
+    assert_eq!(read_output(output).await, Ok(expect));
+}
+
+#[tokio::test]
+async fn read_output_redirect() {
+    let output = &b"3 /other\r\n"[..];
+    let expect = Response::new(
+        StatusCode::Redirect,
+        "/other".to_string(),
+        Some(Body::Buffered(Bytes::new())),
+    );
+
+    assert_eq!(read_output(output).await, Ok(expect));
+}
+
+#[tokio::test]
+async fn read_output_empty() {
+    let output = &b""[..];
+
+    assert_eq!(
+        read_output(output).await,
+        Err(Error::new_upstream(EMPTY_OUTPUT))
+    );
+}
+
+#[test]
+fn cgi_environment_describes_request() {
+    let request = Request::create_from_request_line("example.com /app.cgi?name 5".to_string())
+        .unwrap()
+        .with_addresses(
+            Some("192.168.1.10:45678".parse().unwrap()),
+            Some("0.0.0.0:300".parse().unwrap()),
+        );
+    let script = Script::new(
+        PathBuf::from("./app.cgi"),
+        "/app.cgi".to_string(),
+        String::new(),
+    );
+    let env = cgi_environment(&script, &request);
+    let get = |key: &str| env.iter().find(|(k, _)| *k == key).map(|(_, v)| v.as_str());
+
+    assert_eq!(get("SERVER_NAME"), Some("example.com"));
+    assert_eq!(get("SERVER_PORT"), Some("300"));
+    assert_eq!(get("SERVER_PROTOCOL"), Some("SPARTAN"));
+    assert_eq!(get("SERVER_SOFTWARE"), Some(SERVER_SOFTWARE));
+    assert_eq!(get("REMOTE_ADDR"), Some("192.168.1.10"));
+    assert_eq!(get("SCRIPT_NAME"), Some("/app.cgi"));
+    assert_eq!(get("PATH_INFO"), Some(""));
+    assert_eq!(get("QUERY_STRING"), Some("name"));
+    assert_eq!(get("CONTENT_LENGTH"), Some("5"));
+}
+
+#[test]
+fn cgi_environment_without_addresses() {
+    let request = Request::default();
+    let script = Script::new(PathBuf::from("./index.cgi"), "/".to_string(), String::new());
+    let env = cgi_environment(&script, &request);
+
+    assert!(!env.iter().any(|(k, _)| *k == "REMOTE_ADDR"));
+    assert!(!env.iter().any(|(k, _)| *k == "SERVER_PORT"));
+}
+
+#[cfg(test)]
+fn script_test_root() -> tempfile::TempDir {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = tempfile::tempdir().unwrap();
+    fs::create_dir_all(root.path().join("apps")).unwrap();
+    fs::write(root.path().join("apps/app.cgi"), "#!/bin/sh\n").unwrap();
+    fs::write(root.path().join("apps/page.gmi"), "# Page\n").unwrap();
+    fs::set_permissions(
+        root.path().join("apps/app.cgi"),
+        fs::Permissions::from_mode(0o755),
+    )
+    .unwrap();
+
+    root
+}
+
+#[test]
+fn find_script_exact() {
+    let root = script_test_root();
+
+    assert_eq!(
+        find_script(root.path(), "/apps/app.cgi"),
+        Some(Script::new(
+            root.path().join("apps/app.cgi"),
+            "/apps/app.cgi".to_string(),
+            String::new()
+        ))
+    );
+}
+
+#[test]
+fn find_script_with_path_info() {
+    let root = script_test_root();
+
+    assert_eq!(
+        find_script(root.path(), "/apps/app.cgi/users/42"),
+        Some(Script::new(
+            root.path().join("apps/app.cgi"),
+            "/apps/app.cgi".to_string(),
+            "/users/42".to_string()
+        ))
+    );
+    assert_eq!(
+        find_script(root.path(), "/apps/app.cgi/").map(|s| s.path_info),
+        Some("/".to_string())
+    );
+}
+
+#[test]
+fn find_script_not_executable() {
+    let root = script_test_root();
+
+    assert_eq!(find_script(root.path(), "/apps/page.gmi/users/42"), None);
+    assert_eq!(find_script(root.path(), "/apps/missing.cgi/users"), None);
+    assert_eq!(find_script(root.path(), "/apps/"), None);
+}
+
+#[cfg(test)]
+fn is_process_running(pid: &str) -> bool {
+    // Orphans may stay as zombies if nobody reaps them in container
+    std::fs::read_to_string(format!("/proc/{}/stat", pid)).is_ok_and(|stat| !stat.contains(") Z "))
+}
+
+#[tokio::test]
+async fn aborted_script_stops_its_children() {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = tempfile::tempdir().unwrap();
+    let script = root.path().join("slow.cgi");
+    fs::write(
+        &script,
+        "#!/bin/sh\nsleep 30 &\necho $! > sleep.pid\nwait\n",
+    )
+    .unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let script = Script::new(script, "/slow.cgi".to_string(), String::new());
+    let aborted = tokio::time::timeout(
+        Duration::from_millis(500),
+        process_cgi(script, Request::default()),
+    )
+    .await;
+    assert!(aborted.is_err());
+
+    let pid = fs::read_to_string(root.path().join("sleep.pid")).unwrap();
+    let pid = pid.trim();
+
+    for _ in 0..30 {
+        if !is_process_running(pid) {
+            return;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    panic!("Child of aborted script is still running");
+}
@@ -1,47 +1,153 @@
+use crate::configuration::Configuration;
 use crate::error::{Error, Result};
+use crate::protocol::{PARSE_ERR, REQUEST_TIMEOUT, REQUEST_TOO_LONG};
 
 use bytes::{Bytes, BytesMut};
-use log::debug;
-use std::pin::Pin;
+use core::future::Future;
+use log::{debug, warn};
+use std::time::Duration;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
-    net::TcpStream,
+    io::{
+        split, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader, BufWriter, ReadHalf, WriteHalf,
+    },
+    net::{tcp, unix, TcpStream, UnixStream},
+    time::timeout,
 };
 
+#[cfg(test)]
+use tokio::io::DuplexStream;
+
 /// From procotol spec
-const BUFFER_SIZE: usize = 4096;
+pub const BUFFER_SIZE: usize = 4096;
 
-#[derive(Debug)]
-pub struct Connection {
-    reader: BufReader<&'static mut TcpStream>,
-    writer: BufWriter<&'static mut TcpStream>,
-    _stream: Pin<Box<TcpStream>>,
-}
-
-#[allow(mutable_transmutes)]
-impl Connection {
-    pub fn new(stream: TcpStream) -> Self {
-        let pin = Box::pin(stream);
-
-        unsafe {
-            Self {
-                reader: BufReader::new(std::mem::transmute(&*pin)),
-                writer: BufWriter::new(std::mem::transmute(&*pin)),
-                _stream: pin,
-            }
+const WRITE_TIMEOUT: &str = "Write timeout";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Time for receiving request line
+    pub request: Duration,
+    /// Time for receiving uploaded data
+    pub body: Duration,
+    /// Time that client can don't read response(checked for every written chunk)
+    pub write: Duration,
+}
+
+impl From<&Configuration> for Timeouts {
+    fn from(config: &Configuration) -> Self {
+        Self {
+            request: Duration::from_secs(config.request_timeout),
+            body: Duration::from_secs(config.body_timeout),
+            write: Duration::from_secs(config.write_timeout),
         }
     }
+}
+
+/// Limits operation with time, so slow clients won't hold connection forever
+async fn deadline<T>(
+    duration: Duration,
+    error: &str,
+    future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match timeout(duration, future).await {
+        Ok(result) => result,
+        Err(_) => {
+            warn!("{} after {:?}", error, duration);
+            Err(Error::new_timeout(error))
+        }
+    }
+}
+
+/// Reads line(without CRLF) that isn't longer than limit.
+/// Reading stops on limit, so endless line won't eat all memory
+async fn read_line_limited<R>(reader: R, limit: usize) -> Result<String>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut buffer: Vec<u8> = Vec::with_capacity(limit.min(BUFFER_SIZE));
+
+    // Limit + CRLF
+    let count = io_err!(
+        reader
+            .take((limit + 2) as u64)
+            .read_until(10u8, &mut buffer) // Cause CRLF is ending
+            .await
+    )?;
+
+    debug!("Request read: {} bytes", count);
+
+    let line = String::from_utf8(buffer)
+        .map(|s| s.trim_end().to_string())
+        .map_err(|_| Error::new_bad_request(PARSE_ERR))?;
+
+    if line.len() > limit {
+        warn!("Request line is longer than {} bytes", limit);
+        Err(Error::new_bad_request(REQUEST_TOO_LONG))
+    } else {
+        Ok(line)
+    }
+}
+
+/// Client connection over any duplex stream(TCP, Unix socket, TLS or in-memory one)
+#[derive(Debug)]
+pub struct Connection<R, W> {
+    reader: BufReader<R>,
+    writer: BufWriter<W>,
+    timeouts: Timeouts,
+}
+
+pub type TcpConnection = Connection<tcp::OwnedReadHalf, tcp::OwnedWriteHalf>;
+
+pub type UnixConnection = Connection<unix::OwnedReadHalf, unix::OwnedWriteHalf>;
+
+impl TcpConnection {
+    pub fn from_tcp(stream: TcpStream, timeouts: Timeouts) -> Self {
+        let (reader, writer) = stream.into_split();
+
+        Connection::new(reader, writer, timeouts)
+    }
+}
+
+impl UnixConnection {
+    pub fn from_unix(stream: UnixStream, timeouts: Timeouts) -> Self {
+        let (reader, writer) = stream.into_split();
 
-    fn get_reader(&mut self) -> &mut BufReader<&mut TcpStream> {
-        unsafe { std::mem::transmute(&self.reader) }
+        Connection::new(reader, writer, timeouts)
     }
+}
+
+impl<S> Connection<ReadHalf<S>, WriteHalf<S>>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    pub fn from_stream(stream: S, timeouts: Timeouts) -> Self {
+        let (reader, writer) = split(stream);
+
+        Connection::new(reader, writer, timeouts)
+    }
+}
 
-    fn get_writer(&mut self) -> &mut BufWriter<&mut TcpStream> {
-        unsafe { std::mem::transmute(&self.writer) }
+impl<R, W> Connection<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    pub fn new(reader: R, writer: W, timeouts: Timeouts) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+            timeouts,
+        }
     }
 
-    /// Read N bytes from TcpStream
+    /// Read N bytes from stream
     pub async fn read_count(&mut self, count: usize) -> Result<Bytes> {
+        let duration = self.timeouts.body;
+
+        deadline(duration, REQUEST_TIMEOUT, self.read_count_inner(count)).await
+    }
+
+    async fn read_count_inner(&mut self, count: usize) -> Result<Bytes> {
         let mut left = count;
         let mut buffer: Vec<u8> = Vec::new();
 
@@ -67,7 +173,7 @@ impl Connection {
     async fn read_chunk(&mut self, count: usize) -> Result<Bytes> {
         let mut buffer: Vec<u8> = Vec::with_capacity(count);
         io_err!(
-            self.get_reader()
+            (&mut self.reader)
                 .take(count.try_into().unwrap())
                 .read_buf(&mut buffer)
                 .await
@@ -77,31 +183,177 @@ impl Connection {
     }
 
     /// Function for reading request
-    pub async fn read_line(&mut self) -> Result<String> {
-        let mut buffer: Vec<u8> = Vec::with_capacity(BUFFER_SIZE);
+    pub async fn read_line(&mut self, limit: usize) -> Result<String> {
+        let duration = self.timeouts.request;
 
-        let count = io_err!(
-            self.get_reader()
-                .read_until(10u8, &mut buffer) // Cause CRLF is ending
-                .await
-        )?;
-
-        debug!("Request read: {} bytes", count);
-
-        io_err!(String::from_utf8(buffer).map(|s| s.trim_end().to_string()))
+        deadline(
+            duration,
+            REQUEST_TIMEOUT,
+            read_line_limited(&mut self.reader, limit),
+        )
+        .await
     }
 
     /// Output buffer to socket
     pub async fn write_buf(&mut self, mut buf: BytesMut) -> Result<()> {
-        io_err!(self.get_writer().write_all_buf(&mut buf).await)?;
-        io_err!(self.get_writer().flush().await)?;
+        let duration = self.timeouts.write;
+        let len = buf.len();
+        let writer = &mut self.writer;
+
+        deadline(duration, WRITE_TIMEOUT, async {
+            io_err!(writer.write_all_buf(&mut buf).await)?;
+            io_err!(writer.flush().await)
+        })
+        .await?;
 
-        debug!(
-            "Connection with {}. {} bytes sent",
-            self._stream.peer_addr().unwrap(),
-            buf.len()
-        );
+        debug!("{} bytes sent", len);
 
         Ok(())
     }
+
+    /// Copy everything from reader to socket by chunks
+    pub async fn write_from<F>(&mut self, reader: &mut F) -> Result<u64>
+    where
+        F: AsyncRead + Unpin + ?Sized,
+    {
+        let duration = self.timeouts.write;
+        let mut chunk: Vec<u8> = vec![0; BUFFER_SIZE * 4];
+        let mut count: u64 = 0;
+
+        loop {
+            let read = io_err!(reader.read(&mut chunk).await)?;
+
+            if read == 0 {
+                break;
+            }
+
+            let writer = &mut self.writer;
+            deadline(duration, WRITE_TIMEOUT, async {
+                io_err!(writer.write_all(&chunk[..read]).await)
+            })
+            .await?;
+
+            count += read as u64;
+        }
+
+        let writer = &mut self.writer;
+        deadline(duration, WRITE_TIMEOUT, async {
+            io_err!(writer.flush().await)
+        })
+        .await?;
+
+        debug!("{} bytes streamed", count);
+
+        Ok(count)
+    }
+}
+
+// ----------------- Tests section --------------------
+
+#[cfg(test)]
+type TestConnection = Connection<ReadHalf<DuplexStream>, WriteHalf<DuplexStream>>;
+
+#[cfg(test)]
+fn test_connection(timeouts: Timeouts) -> (TestConnection, DuplexStream) {
+    let (server, client) = tokio::io::duplex(BUFFER_SIZE);
+
+    (Connection::from_stream(server, timeouts), client)
+}
+
+#[cfg(test)]
+const TEST_TIMEOUTS: Timeouts = Timeouts {
+    request: Duration::from_millis(50),
+    body: Duration::from_millis(50),
+    write: Duration::from_millis(50),
+};
+
+#[tokio::test]
+async fn read_line_timeout() {
+    let (mut connection, _client) = test_connection(TEST_TIMEOUTS);
+
+    assert_eq!(
+        connection.read_line(BUFFER_SIZE).await,
+        Err(Error::new_timeout(REQUEST_TIMEOUT))
+    );
+}
+
+#[tokio::test]
+async fn read_line_in_time() {
+    let (mut connection, mut client) = test_connection(TEST_TIMEOUTS);
+    client.write_all(b"localhost / 0\r\n").await.unwrap();
+
+    assert_eq!(
+        connection.read_line(BUFFER_SIZE).await,
+        Ok("localhost / 0".to_string())
+    );
+}
+
+#[tokio::test]
+async fn read_count_timeout() {
+    let (mut connection, mut client) = test_connection(TEST_TIMEOUTS);
+    client.write_all(b"hello").await.unwrap();
+
+    assert_eq!(
+        connection.read_count(10).await,
+        Err(Error::new_timeout(REQUEST_TIMEOUT))
+    );
+}
+
+#[tokio::test]
+async fn read_line_limited_exact_limit() {
+    let line = &b"host /abc 0\r\n"[..];
+
+    assert_eq!(
+        read_line_limited(line, 11).await,
+        Ok("host /abc 0".to_string())
+    );
+}
+
+#[tokio::test]
+async fn read_line_limited_over_limit() {
+    let line = &b"host /abcd 0\r\n"[..];
+
+    assert_eq!(
+        read_line_limited(line, 11).await,
+        Err(Error::new_bad_request(REQUEST_TOO_LONG))
+    );
+}
+
+#[tokio::test]
+async fn read_line_limited_without_line_end() {
+    let line = vec![b'a'; BUFFER_SIZE * 4];
+
+    assert_eq!(
+        read_line_limited(&line[..], BUFFER_SIZE).await,
+        Err(Error::new_bad_request(REQUEST_TOO_LONG))
+    );
+}
+
+#[tokio::test]
+async fn write_buf_and_stream() {
+    let (mut connection, mut client) = test_connection(TEST_TIMEOUTS);
+    let mut body = &b"# Hello\r\n"[..];
+
+    connection
+        .write_buf(BytesMut::from(&b"2 text/gemini\r\n"[..]))
+        .await
+        .unwrap();
+    assert_eq!(connection.write_from(&mut body).await, Ok(9));
+    drop(connection);
+
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+
+    assert_eq!(received, b"2 text/gemini\r\n# Hello\r\n".to_vec());
+}
+
+#[tokio::test]
+async fn write_from_timeout_on_slow_reader() {
+    let (mut connection, _client) = test_connection(TEST_TIMEOUTS);
+    let content = vec![0u8; BUFFER_SIZE * 8];
+
+    assert_eq!(
+        connection.write_from(&mut &content[..]).await,
+        Err(Error::new_timeout(WRITE_TIMEOUT))
+    );
 }
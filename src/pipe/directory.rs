@@ -1,27 +1,63 @@
 use crate::error::{Error, Result};
+use crate::mime::GEMINI_MIME;
 use crate::pipe::file::{process_file, read_file};
-use crate::pipe::router::get_root_dir;
+use crate::pipe::hidden::is_hidden_name;
+use crate::pipe::resolver::{resolve_listfiles, resolve_locator};
+use crate::pipe::vhost::HostSettings;
 use crate::protocol::request::Request;
 use crate::protocol::response::Response;
 use crate::protocol::NOT_ALLOWED;
 use bytes::Bytes;
+use serde::Deserialize;
+use std::fmt;
 use urlencoding::encode;
 
-async fn process_directory_list(host: String, locator: String) -> Result<Response> {
-    let mut path = get_root_dir().await?;
-    path.push(host);
-    path.push(&locator.as_str()[1..]);
+/// When directory without index file is shown as list
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DirectoryListing {
+    Never,
+    /// Only directories with .listfiles(it's shown as header of list)
+    #[default]
+    Listfiles,
+    /// Every directory, .listfiles is optional
+    Always,
+}
+
+impl fmt::Display for DirectoryListing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DirectoryListing::Never => write!(f, "never"),
+            DirectoryListing::Listfiles => write!(f, "listfiles"),
+            DirectoryListing::Always => write!(f, "always"),
+        }
+    }
+}
 
-    let mut file_path = path.clone();
-    file_path.push(".listfiles");
+async fn process_directory_list(host: &HostSettings, locator: String) -> Result<Response> {
+    // Missing directory is reported as missing even if listing is disabled
+    let path = resolve_locator(host, locator.as_str())?;
 
-    let mut header = read_file(file_path)
-        .await
-        .map_err(|_| Error::new_request_error(NOT_ALLOWED))?;
+    if host.directory_listing == DirectoryListing::Never {
+        return Err(Error::new_forbidden(NOT_ALLOWED));
+    }
 
-    let mut nl: Vec<u8> = vec![13, 10];
+    // Refused .listfiles(symlink out of root or to hidden file) is same as missing one
+    let header = match resolve_listfiles(host, &path, locator.as_str()) {
+        Ok(file_path) => read_file(file_path).await,
+        Err(e) => Err(e),
+    };
 
-    header.append(&mut nl);
+    let mut header = match header {
+        Ok(mut header) => {
+            let mut nl: Vec<u8> = vec![13, 10];
+            header.append(&mut nl);
+
+            header
+        }
+        Err(_) if host.directory_listing == DirectoryListing::Always => Vec::new(),
+        Err(_) => return Err(Error::new_forbidden(NOT_ALLOWED)),
+    };
 
     // It works and works faster than tokio::fs
     io_err!(std::fs::read_dir(path))?
@@ -32,6 +68,17 @@ async fn process_directory_list(host: String, locator: String) -> Result<Respons
             let is_dir = entry.file_type().unwrap().is_dir();
             let name = file_name.to_str().unwrap();
 
+            if is_hidden_name(name, is_dir, host.hide_dotfiles, &host.hidden_files) {
+                return;
+            }
+
+            // Symlink is listed only if it can be served(target isn't hidden or denied)
+            if entry.file_type().unwrap().is_symlink()
+                && resolve_locator(host, format!("{}{}", locator, name).as_str()).is_err()
+            {
+                return;
+            }
+
             let line = if is_dir {
                 format!("=> {}{}/ <{}>\r\n", locator, encode(name), name)
             } else {
@@ -45,14 +92,13 @@ async fn process_directory_list(host: String, locator: String) -> Result<Respons
         });
 
     Ok(Response::new_success(
-        "text/gemini".to_string(),
+        host.text_mime(GEMINI_MIME.to_string()),
         Bytes::from(header),
     ))
 }
 
-pub async fn process_directory(request: Request) -> Result<Response> {
+pub async fn process_directory(request: Request, host: &HostSettings) -> Result<Response> {
     let locator = request.locator.clone();
-    let host = request.host.clone();
 
     let mut index_gmi_req = request.clone();
     let mut index_txt_req = request.clone();
@@ -60,8 +106,8 @@ pub async fn process_directory(request: Request) -> Result<Response> {
     index_gmi_req.locator = format!("{}index.gmi", locator);
     index_txt_req.locator = format!("{}index.txt", locator);
 
-    process_file(index_gmi_req)
+    process_file(index_gmi_req, host)
         .await
-        .or(process_file(index_txt_req).await)
+        .or(process_file(index_txt_req, host).await)
         .or(process_directory_list(host, locator).await)
 }
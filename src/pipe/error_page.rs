@@ -0,0 +1,211 @@
+use crate::error::{Error, ErrorKind};
+use crate::protocol::response::{Response, StatusCode};
+
+use serde::Deserialize;
+use urlencoding::decode;
+
+/// Replaced with default message in templates: "example.com: {message}"
+const MESSAGE_PLACEHOLDER: &str = "{message}";
+
+/// Status line texts for error responses of host. Spartan errors can't have body,
+/// so only status line can be changed
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ErrorPages {
+    pub not_found: Option<String>,
+    pub not_allowed: Option<String>,
+    /// Broken request, too big upload and timeouts
+    pub bad_request: Option<String>,
+    pub script_error: Option<String>,
+    pub server_error: Option<String>,
+    /// Locator of "not found" page. Missing files are redirected to it instead of error
+    pub not_found_redirect: Option<String>,
+}
+
+impl ErrorPages {
+    /// Own templates win, missing ones are taken from other(global) pages
+    pub fn or(self, other: &ErrorPages) -> ErrorPages {
+        ErrorPages {
+            not_found: self.not_found.or_else(|| other.not_found.clone()),
+            not_allowed: self.not_allowed.or_else(|| other.not_allowed.clone()),
+            bad_request: self.bad_request.or_else(|| other.bad_request.clone()),
+            script_error: self.script_error.or_else(|| other.script_error.clone()),
+            server_error: self.server_error.or_else(|| other.server_error.clone()),
+            not_found_redirect: self
+                .not_found_redirect
+                .or_else(|| other.not_found_redirect.clone()),
+        }
+    }
+
+    fn templates(&self) -> [(&str, Option<&String>); 6] {
+        [
+            ("not_found", self.not_found.as_ref()),
+            ("not_allowed", self.not_allowed.as_ref()),
+            ("bad_request", self.bad_request.as_ref()),
+            ("script_error", self.script_error.as_ref()),
+            ("server_error", self.server_error.as_ref()),
+            ("not_found_redirect", self.not_found_redirect.as_ref()),
+        ]
+    }
+
+    /// Status line can't be empty or contain line breaks.
+    /// Redirect target should be locator on same host
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some((name, _)) = self.templates().into_iter().find(|(_, template)| {
+            template.is_some_and(|t| t.trim().is_empty() || t.contains(['\r', '\n']))
+        }) {
+            return Err(format!("Wrong error template {}", name));
+        }
+
+        match &self.not_found_redirect {
+            Some(target) if !target.starts_with('/') || target.starts_with("//") => Err(format!(
+                "Not found redirect {} should be locator starting with /",
+                target
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Request for redirect page itself(or its directory with or without slash)
+    fn is_redirect_target(target: &str, locator: &str) -> bool {
+        let path = target.split('?').next().unwrap_or_default();
+        let path = decode(path).map_or(path.to_string(), |p| p.to_string());
+
+        path.trim_end_matches('/') == locator.trim_end_matches('/')
+    }
+
+    fn template(&self, kind: ErrorKind) -> Option<&String> {
+        match kind {
+            ErrorKind::NotFound => self.not_found.as_ref(),
+            ErrorKind::Forbidden => self.not_allowed.as_ref(),
+            ErrorKind::BadRequest | ErrorKind::Timeout => self.bad_request.as_ref(),
+            ErrorKind::Upstream => self.script_error.as_ref(),
+            ErrorKind::Io | ErrorKind::Other | ErrorKind::Unexpected => self.server_error.as_ref(),
+        }
+    }
+
+    /// Response for error of request to locator. Redirect page itself is never
+    /// redirected, so missing page won't make loop
+    pub fn response(&self, error: &Error, locator: &str) -> Response {
+        if let Some(target) = &self.not_found_redirect {
+            if error.kind() == ErrorKind::NotFound && !Self::is_redirect_target(target, locator) {
+                return Response::new_redirect(target.clone());
+            }
+        }
+
+        let response = Response::from_error(error);
+
+        match self.template(error.kind()) {
+            Some(template) => {
+                let line = template.replace(MESSAGE_PLACEHOLDER, response.status_line.as_str());
+
+                match response.status_code {
+                    StatusCode::ClientError => Response::new_client_error(line),
+                    _ => Response::new_server_error(line),
+                }
+            }
+            None => response,
+        }
+    }
+}
+
+// ----------------- Tests section --------------------
+
+#[cfg(test)]
+fn test_pages() -> ErrorPages {
+    ErrorPages {
+        not_found: Some("Nothing here on example.com".to_string()),
+        bad_request: Some("example.com: {message}".to_string()),
+        server_error: Some("example.com is broken, try later".to_string()),
+        ..ErrorPages::default()
+    }
+}
+
+#[test]
+fn response_without_templates() {
+    let error = Error::new_not_found("/missing.gmi");
+
+    assert_eq!(
+        ErrorPages::default().response(&error, "/missing.gmi"),
+        Response::from_error(&error)
+    );
+}
+
+#[test]
+fn response_with_templates() {
+    let pages = test_pages();
+
+    assert_eq!(
+        pages.response(&Error::new_not_found("/missing.gmi"), "/missing.gmi"),
+        Response::new_client_error("Nothing here on example.com".to_string())
+    );
+    assert_eq!(
+        pages.response(&Error::new_timeout("Request timeout"), "/"),
+        Response::new_client_error("example.com: Request timeout".to_string())
+    );
+    assert_eq!(
+        pages.response(&Error::new_io("Disk is full"), "/"),
+        Response::new_server_error("example.com is broken, try later".to_string())
+    );
+    // Category without template keeps default message
+    assert_eq!(
+        pages.response(&Error::new_upstream("Script failed"), "/app.cgi"),
+        Response::from_error(&Error::new_upstream("Script failed"))
+    );
+}
+
+#[test]
+fn response_not_found_redirect() {
+    let pages = ErrorPages {
+        not_found_redirect: Some("/404.gmi".to_string()),
+        ..test_pages()
+    };
+
+    assert_eq!(
+        pages.response(&Error::new_not_found("/missing.gmi"), "/missing.gmi"),
+        Response::new_redirect("/404.gmi".to_string())
+    );
+    assert_eq!(
+        pages.response(&Error::new_not_found("/404.gmi"), "/404.gmi"),
+        Response::new_client_error("Nothing here on example.com".to_string())
+    );
+    assert_eq!(
+        pages.response(&Error::new_forbidden("Not allowed"), "/private/"),
+        Response::from_error(&Error::new_forbidden("Not allowed"))
+    );
+
+    // Directory as target is requested with slash after trailing-slash redirect
+    let pages = ErrorPages {
+        not_found_redirect: Some("/not%20found?from=404".to_string()),
+        ..test_pages()
+    };
+
+    assert_eq!(
+        pages.response(&Error::new_not_found("/not found/"), "/not found/"),
+        Response::new_client_error("Nothing here on example.com".to_string())
+    );
+}
+
+#[test]
+fn validate_templates() {
+    let broken = ErrorPages {
+        not_allowed: Some("Go away\r\n2 text/gemini".to_string()),
+        ..ErrorPages::default()
+    };
+
+    assert_eq!(test_pages().validate(), Ok(()));
+    assert!(broken.validate().is_err());
+    for target in [
+        " ",
+        "404.gmi",
+        "//other.host/404.gmi",
+        "spartan://other.host/",
+    ] {
+        assert!(ErrorPages {
+            not_found_redirect: Some(target.to_string()),
+            ..ErrorPages::default()
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -1,93 +1,102 @@
 use crate::error::{Error, Result};
-use crate::mime::filename_to_mime;
-use crate::pipe::router::get_root_dir;
+use crate::mime::path_to_mime;
+use crate::pipe::cgi::{process_cgi, Script};
+use crate::pipe::resolver::resolve_locator;
+use crate::pipe::vhost::HostSettings;
 use crate::protocol::request::Request;
-use crate::protocol::response::{Response, StatusCode};
+use crate::protocol::response::Response;
 use crate::protocol::NOT_ALLOWED;
-use bytes::Bytes;
 use is_executable::IsExecutable;
 use log::debug;
-use std::io::{BufRead, BufReader, Read, Write};
 use std::path::PathBuf;
-use std::process::{Command, Stdio};
 use tokio::fs;
 
 pub async fn read_file(path: PathBuf) -> Result<Vec<u8>> {
     io_err!(fs::read(path).await)
 }
 
-async fn process_plain_file(file_path: PathBuf) -> Result<Response> {
+async fn process_plain_file(file_path: PathBuf, host: &HostSettings) -> Result<Response> {
     debug!("Processing file: {}", file_path.to_string_lossy());
 
-    let mime = filename_to_mime(file_path.to_string_lossy().to_string());
-    let content = read_file(file_path).await?;
+    let metadata = io_err!(fs::metadata(file_path.clone()).await)?;
 
-    Ok(Response::new_success(mime, Bytes::from(content)))
+    if !metadata.is_file() {
+        return Err(Error::new_not_found(
+            format!("Not a file: {}", file_path.to_string_lossy()).as_str(),
+        ));
+    }
+
+    let mime = host.text_mime(path_to_mime(&file_path, &host.mime_types));
+
+    Ok(Response::new_file(mime, file_path))
 }
 
 fn is_executable(path: PathBuf) -> bool {
     path.is_executable()
 }
 
-async fn process_cgi(path: PathBuf, request: Request) -> Result<Response> {
-    debug!("Executed cgi: {}", path.to_string_lossy());
-
-    let data = request.data.unwrap_or_default();
-
-    let result = io_err!(Command::new(path.clone())
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .current_dir(path.parent().unwrap().as_os_str())
-        .spawn())?;
-
-    let mut stdin = result.stdin.unwrap();
+pub async fn process_file(request: Request, host: &HostSettings) -> Result<Response> {
+    let locator = request.locator.clone();
 
-    io_err!(stdin.write(&data[..]))?;
-    io_err!(stdin.flush())?;
+    let file_path = resolve_locator(host, locator.as_str())?;
 
-    drop(stdin);
+    // Without CGI executables are usual files
+    if host.cgi && is_executable(file_path.clone()) {
+        let script = Script::new(file_path, locator, String::new());
+        process_cgi(script, request).await
+    } else if request.data_len > 0 {
+        Err(Error::new_forbidden(NOT_ALLOWED))
+    } else {
+        process_plain_file(file_path, host).await
+    }
+}
 
-    let mut buf: Vec<u8> = Vec::new();
-    let mut output = result.stdout.unwrap();
+// ----------------- Tests section --------------------
 
-    let mut reader = BufReader::new(&mut output);
+#[cfg(test)]
+fn test_host() -> HostSettings {
+    use crate::configuration::Configuration;
+    use crate::pipe::vhost::VirtualHost;
 
-    let mut status_code_b: Vec<u8> = Vec::with_capacity(2);
-    let mut status_line: Vec<u8> = Vec::new();
+    let vhost = VirtualHost::new("localhost", PathBuf::from("./example/localhost"));
 
-    io_err!(reader.read_until(32u8, &mut status_code_b))?;
+    HostSettings::new(&Configuration::default(), vhost)
+}
 
-    // align char numbers
-    let status_code_num = *status_code_b.first().unwrap() - 48;
-    let status_code = StatusCode::from_number(status_code_num);
+#[tokio::test]
+async fn process_plain_file_streams_from_disk() {
+    let path = PathBuf::from("./example/localhost/files/LICENSE");
+    let result = process_plain_file(path.clone(), &test_host()).await;
+
+    assert_eq!(
+        result,
+        Ok(Response::new_file(
+            "application/octet-stream".to_string(),
+            path
+        ))
+    );
+}
 
-    io_err!(reader.read_until(10u8, &mut status_line))?;
+#[tokio::test]
+async fn process_plain_file_missing() {
+    let path = PathBuf::from("./example/localhost/not-exists.gmi");
 
-    let status_line_str =
-        io_err!(String::from_utf8(status_line).map(|s| s.trim_end().to_string()))?;
+    assert!(process_plain_file(path, &test_host()).await.is_err());
+}
 
-    io_err!(reader.read_to_end(&mut buf))?;
+#[tokio::test]
+async fn process_plain_file_directory() {
+    let path = PathBuf::from("./example/localhost/files");
 
-    Ok(Response::new(
-        status_code,
-        status_line_str,
-        Some(Bytes::from(buf)),
-    ))
+    assert!(process_plain_file(path, &test_host()).await.is_err());
 }
 
-pub async fn process_file(request: Request) -> Result<Response> {
-    let host = request.host.clone();
-    let locator = request.locator.clone();
+#[tokio::test]
+async fn process_plain_file_gemtext() {
+    let path = PathBuf::from("./example/localhost/index.gmi");
 
-    let mut file_path = get_root_dir().await?;
-    file_path.push(host);
-    file_path.push(&locator.as_str()[1..]);
-
-    if is_executable(file_path.clone()) {
-        process_cgi(file_path, request).await
-    } else if request.data_len > 0 {
-        Ok(Response::new_client_error(NOT_ALLOWED.to_string()))
-    } else {
-        process_plain_file(file_path).await
-    }
+    assert_eq!(
+        process_plain_file(path.clone(), &test_host()).await,
+        Ok(Response::new_file("text/gemini".to_string(), path))
+    );
 }
@@ -0,0 +1,125 @@
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+
+/// Glob for files that shouldn't be served or listed: "*~", "*.bak", ".git/".
+/// Pattern is matched against every part of locator, "/" on end matches directories only
+#[derive(Clone, Debug)]
+pub struct HiddenPattern {
+    pattern: String,
+    regex: Regex,
+    directory_only: bool,
+}
+
+impl TryFrom<String> for HiddenPattern {
+    type Error = String;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        let (glob, directory_only) = match pattern.strip_suffix('/') {
+            Some(glob) => (glob, true),
+            None => (pattern.as_str(), false),
+        };
+
+        if glob.is_empty() || glob.contains('/') {
+            return Err(format!("Wrong hidden files pattern {}", pattern));
+        }
+
+        let expression = regex::escape(glob)
+            .replace("\\*", "[^/]*")
+            .replace("\\?", "[^/]");
+
+        Regex::new(format!("^{}$", expression).as_str())
+            .map(|regex| HiddenPattern {
+                pattern: pattern.clone(),
+                regex,
+                directory_only,
+            })
+            .map_err(|e| format!("Wrong hidden files pattern {}: {}", pattern, e))
+    }
+}
+
+impl<'de> Deserialize<'de> for HiddenPattern {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let pattern = String::deserialize(deserializer)?;
+
+        HiddenPattern::try_from(pattern).map_err(serde::de::Error::custom)
+    }
+}
+
+impl fmt::Display for HiddenPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.pattern)
+    }
+}
+
+impl HiddenPattern {
+    pub fn matches(&self, name: &str, is_dir: bool) -> bool {
+        (is_dir || !self.directory_only) && self.regex.is_match(name)
+    }
+}
+
+/// Checks one file or directory name(used for directory listings too)
+pub fn is_hidden_name(
+    name: &str,
+    is_dir: bool,
+    dotfiles: bool,
+    patterns: &[HiddenPattern],
+) -> bool {
+    (dotfiles && name.starts_with('.')) || patterns.iter().any(|p| p.matches(name, is_dir))
+}
+
+/// Locator is hidden if any part of it is hidden
+pub fn is_hidden_locator(locator: &str, dotfiles: bool, patterns: &[HiddenPattern]) -> bool {
+    let names: Vec<&str> = locator.split('/').filter(|n| !n.is_empty()).collect();
+    let last = names.len().saturating_sub(1);
+
+    names.iter().enumerate().any(|(index, name)| {
+        let is_dir = index < last || locator.ends_with('/');
+
+        is_hidden_name(name, is_dir, dotfiles, patterns)
+    })
+}
+
+// ----------------- Tests section --------------------
+
+#[cfg(test)]
+fn test_patterns() -> Vec<HiddenPattern> {
+    ["*~", "*.bak", ".git/", "secret?.txt"]
+        .into_iter()
+        .map(|p| HiddenPattern::try_from(p.to_string()).unwrap())
+        .collect()
+}
+
+#[test]
+fn hidden_pattern_wrong() {
+    assert!(HiddenPattern::try_from("".to_string()).is_err());
+    assert!(HiddenPattern::try_from("/".to_string()).is_err());
+    assert!(HiddenPattern::try_from("docs/*.bak".to_string()).is_err());
+}
+
+#[test]
+fn is_hidden_locator_dotfiles() {
+    assert!(is_hidden_locator("/.listfiles", true, &[]));
+    assert!(is_hidden_locator("/.hidden/index.gmi", true, &[]));
+    assert!(!is_hidden_locator("/.listfiles", false, &[]));
+    assert!(!is_hidden_locator("/files/index.gmi", true, &[]));
+    assert!(!is_hidden_locator("/", true, &[]));
+}
+
+#[test]
+fn is_hidden_locator_patterns() {
+    let patterns = test_patterns();
+
+    assert!(is_hidden_locator("/index.gmi~", false, &patterns));
+    assert!(is_hidden_locator("/files/old.bak", false, &patterns));
+    assert!(is_hidden_locator("/.git/", false, &patterns));
+    assert!(is_hidden_locator("/.git/config", false, &patterns));
+    assert!(is_hidden_locator("/secret1.txt", false, &patterns));
+    assert!(!is_hidden_locator("/secret12.txt", false, &patterns));
+    assert!(!is_hidden_locator("/files/bak", false, &patterns));
+    // ".git/" matches directories only
+    assert!(!is_hidden_locator("/.git", false, &patterns));
+}
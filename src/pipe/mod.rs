@@ -1,76 +1,174 @@
+pub mod cgi;
 pub mod connection;
 pub mod directory;
+pub mod error_page;
 pub mod file;
+pub mod hidden;
+pub mod redirect;
+pub mod resolver;
 pub mod router;
+pub mod vhost;
 
 use crate::configuration::SETTINGS;
 use crate::error::{Error, Result};
 use crate::protocol::request::Request;
-use crate::protocol::response::Response;
-use crate::protocol::UPLOAD_TOO_BIG;
+use crate::protocol::response::{Body, Response, StatusCode};
+use crate::protocol::{NOT_SERVED, UPLOAD_TOO_BIG};
 use core::future::Future;
-use router::route;
+use router::{resolve_host, route};
+use vhost::HostSettings;
 
 use bytes::BytesMut;
 use connection::Connection;
+use log::{error, info};
+use std::net::SocketAddr;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncWrite};
 
-fn error_handler_middleware(result: Result<Response>) -> Response {
+/// Client gets only sanitized message, details stay in log
+fn log_error(error: &Error, response: &Response) {
+    if response.status_code == StatusCode::ServerError {
+        error!("Request failed: {}", error);
+    } else {
+        info!("Request failed: {}", error);
+    }
+}
+
+/// Response for errors that happen before host is known
+fn error_response(error: Error) -> Response {
+    let response = Response::from_error(&error);
+    log_error(&error, &response);
+
+    response
+}
+
+/// Errors of served host are answered with its error pages
+fn error_handler_middleware(
+    result: Result<(Response, Option<File>)>,
+    host: &HostSettings,
+    locator: &str,
+) -> (Response, Option<File>) {
     match result {
-        Err(e) => Response::new_server_error(e.to_string()),
+        Err(e) => {
+            let response = host.errors.response(&e, locator);
+            log_error(&e, &response);
+
+            (response, None)
+        }
         Ok(r) => r,
     }
 }
 
-async fn request_data_loader<T>(
+async fn request_data_loader<T, R, W>(
     request: Request,
-    connection: &mut Connection,
+    connection: &mut Connection<R, W>,
     fun: impl Fn(Request) -> T,
 ) -> Result<Response>
 where
     T: Future<Output = Result<Response>>,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
 {
     let count = request.data_len;
 
     if count == 0 {
         fun(request).await
     } else {
-        let datum = connection.read_count(count).await?;
-        let req = request.append_data(datum)?;
+        // Late or short upload is client's fault, broken connection won't get answer anyway
+        let req = connection
+            .read_count(count)
+            .await
+            .and_then(|datum| request.append_data(datum))?;
+
         fun(req).await
     }
 }
 
-async fn upload_data_size_check(request: Request) -> Result<Request> {
-    let max_allowed = SETTINGS.read().await.to_owned().max_upload_size;
+fn upload_data_size_check(request: Request, host: &HostSettings) -> Result<Request> {
+    let max_allowed = host.max_upload_size;
     if max_allowed >= request.data_len {
         Ok(request)
     } else {
-        Err(Error::new_request_error(UPLOAD_TOO_BIG))
+        Err(Error::new_bad_request(UPLOAD_TOO_BIG))
     }
 }
 
-pub async fn handler(connection: &mut Connection) -> Result<Response> {
-    let req_string: String = connection.read_line().await?.trim_end().to_string();
+pub async fn handler<R, W>(
+    connection: &mut Connection<R, W>,
+    remote_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+) -> Result<Response>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let max_request_line = SETTINGS.read().await.max_request_line;
+
+    let request = connection
+        .read_line(max_request_line)
+        .await
+        .and_then(|line| Request::create_from_request_line(line.trim_end().to_string()))
+        .map(|r| r.with_addresses(remote_addr, local_addr));
 
-    let request = match Request::create_from_request_line(req_string) {
-        Ok(r) => upload_data_size_check(r).await,
-        Err(r) => Err(r),
-    };
+    let (response, file) = match request {
+        Err(e) => (error_response(e), None),
+        // Host is resolved before reading data, cause upload limit belongs to host
+        Ok(req) => match resolve_host(&req).await {
+            Some(host) => {
+                let locator = req.locator.clone();
+                let result = match upload_data_size_check(req, &host) {
+                    Err(e) => Err(e),
+                    Ok(req) => {
+                        request_data_loader(req, connection, |r| route(r, host.clone())).await
+                    }
+                };
 
-    let response = match request {
-        Err(e) => Response::new_client_error(e.to_string()),
-        Ok(req) => {
-            error_handler_middleware(request_data_loader(req.clone(), connection, route).await)
-        }
+                let result = match result {
+                    Ok(response) => open_body(response).await,
+                    Err(e) => Err(e),
+                };
+
+                error_handler_middleware(result, &host, locator.as_str())
+            }
+            None => (error_response(Error::new_bad_request(NOT_SERVED)), None),
+        },
     };
 
+    write_response(connection, response, file).await
+}
+
+/// Opens streamed body before sending header, so missing file still can be reported to client
+async fn open_body(response: Response) -> Result<(Response, Option<File>)> {
+    match response.content {
+        Some(Body::File(ref path)) => match File::open(path).await {
+            Ok(file) => Ok((response, Some(file))),
+            Err(e) => {
+                let error = format!("Can't open {}: {}", path.to_string_lossy(), e);
+                let kind = Error::from(e).kind();
+
+                Err(Error::new(kind, error.as_str()))
+            }
+        },
+        _ => Ok((response, None)),
+    }
+}
+
+async fn write_response<R, W>(
+    connection: &mut Connection<R, W>,
+    response: Response,
+    file: Option<File>,
+) -> Result<Response>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
     let header = response.render_header();
-    let content = response.content.clone();
 
     connection.write_buf(BytesMut::from(&header[..])).await?;
 
-    match content {
-        Some(v) => connection.write_buf(BytesMut::from(&v[..])).await,
+    match (&response.content, file) {
+        (Some(Body::Buffered(v)), _) => connection.write_buf(BytesMut::from(&v[..])).await,
+        (Some(Body::File(_)), Some(mut f)) => connection.write_from(&mut f).await.map(|_| ()),
         _ => Ok(()),
     }?;
 
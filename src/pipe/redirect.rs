@@ -0,0 +1,192 @@
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use urlencoding::encode;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RedirectKind {
+    Exact,
+    Prefix,
+    Regex,
+}
+
+/// Redirect rule as it written in configuration file
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedirectRule {
+    pub kind: RedirectKind,
+    pub from: String,
+    pub to: String,
+}
+
+/// Ready to use redirect rule(regular expressions are already compiled)
+#[derive(Clone, Debug)]
+pub enum Redirect {
+    Exact { from: String, to: String },
+    Prefix { from: String, to: String },
+    Regex { from: Regex, to: String },
+}
+
+impl TryFrom<RedirectRule> for Redirect {
+    type Error = String;
+
+    fn try_from(rule: RedirectRule) -> Result<Self, Self::Error> {
+        // Target goes to status line as is
+        if rule.to.contains(['\r', '\n']) {
+            return Err(format!("Redirect target of {} has line break", rule.from));
+        }
+
+        match rule.kind {
+            RedirectKind::Exact => Ok(Redirect::Exact {
+                from: rule.from,
+                to: rule.to,
+            }),
+            RedirectKind::Prefix => Ok(Redirect::Prefix {
+                from: rule.from,
+                to: rule.to,
+            }),
+            RedirectKind::Regex => Regex::new(rule.from.as_str())
+                .map(|from| Redirect::Regex { from, to: rule.to })
+                .map_err(|e| format!("Wrong redirect regex {}: {}", rule.from, e)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Redirect {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let rule = RedirectRule::deserialize(deserializer)?;
+
+        Redirect::try_from(rule).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Locator is decoded, so parts of it are encoded back before sending to client
+pub fn encode_locator(locator: &str) -> String {
+    locator
+        .split('/')
+        .map(|part| encode(part).into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Same as regex replacement($1, $name, ${name} and $$), but captured parts
+/// of locator are encoded
+fn expand_encoded(captures: &Captures, to: &str) -> String {
+    let mut location = String::new();
+    let mut rest = to;
+
+    while let Some(position) = rest.find('$') {
+        location.push_str(&rest[..position]);
+        rest = &rest[position + 1..];
+
+        if let Some(tail) = rest.strip_prefix('$') {
+            location.push('$');
+            rest = tail;
+            continue;
+        }
+
+        let (name, tail) = match rest.strip_prefix('{').and_then(|r| r.split_once('}')) {
+            Some(parts) => parts,
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+
+                rest.split_at(end)
+            }
+        };
+
+        if name.is_empty() {
+            location.push('$');
+            continue;
+        }
+
+        let group = match name.parse::<usize>() {
+            Ok(index) => captures.get(index),
+            Err(_) => captures.name(name),
+        };
+
+        if let Some(group) = group {
+            location.push_str(encode_locator(group.as_str()).as_str());
+        }
+
+        rest = tail;
+    }
+
+    location.push_str(rest);
+    location
+}
+
+impl Redirect {
+    /// Returns new location if rule matches locator. Target from configuration
+    /// is used as is, parts taken from locator are encoded
+    pub fn apply(&self, locator: &str) -> Option<String> {
+        match self {
+            Redirect::Exact { from, to } => (locator == from).then(|| to.clone()),
+            Redirect::Prefix { from, to } => locator
+                .strip_prefix(from.as_str())
+                .map(|rest| format!("{}{}", to, encode_locator(rest))),
+            Redirect::Regex { from, to } => from.captures(locator).and_then(|captures| {
+                let matched = captures.get(0)?;
+
+                Some(format!(
+                    "{}{}{}",
+                    encode_locator(&locator[..matched.start()]),
+                    expand_encoded(&captures, to),
+                    encode_locator(&locator[matched.end()..])
+                ))
+            }),
+        }
+    }
+}
+
+/// First matched rule wins. Query of request is kept, unless target has own one
+pub fn find_redirect(rules: &[Redirect], locator: &str, query: Option<&str>) -> Option<String> {
+    let location = rules.iter().find_map(|rule| rule.apply(locator))?;
+
+    match query {
+        Some(query) if !location.contains('?') => Some(format!("{}?{}", location, query)),
+        _ => Some(location),
+    }
+}
+
+// ----------------- Tests section --------------------
+
+#[cfg(test)]
+fn test_rule(kind: RedirectKind, from: &str, to: &str) -> Result<Redirect, String> {
+    Redirect::try_from(RedirectRule {
+        kind,
+        from: from.to_string(),
+        to: to.to_string(),
+    })
+}
+
+#[test]
+fn redirect_target_with_line_break() {
+    assert!(test_rule(RedirectKind::Exact, "/old.gmi", "/new.gmi\r\n2 text/gemini").is_err());
+    assert!(test_rule(RedirectKind::Prefix, "/docs/", "/files/\n").is_err());
+    assert!(test_rule(RedirectKind::Regex, "^/a$", "/b").is_ok());
+}
+
+#[test]
+fn regex_redirect_expansion() {
+    let rule = test_rule(
+        RedirectKind::Regex,
+        "^/u/(?P<name>[^/]+)/(.*)$",
+        "/~${name}/$2?$$",
+    )
+    .unwrap();
+
+    assert_eq!(
+        rule.apply("/u/al ice/my notes.gmi"),
+        Some("/~al%20ice/my%20notes.gmi?$".to_string())
+    );
+
+    // Not matched parts of locator are encoded too
+    let rule = test_rule(RedirectKind::Regex, "^/old", "/new").unwrap();
+
+    assert_eq!(rule.apply("/old dir/x"), Some("/new%20dir/x".to_string()));
+}
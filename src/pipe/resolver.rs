@@ -0,0 +1,266 @@
+use crate::error::{Error, Result};
+use crate::pipe::hidden::is_hidden_locator;
+use crate::pipe::vhost::HostSettings;
+
+use log::warn;
+use serde::Deserialize;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+const OUTSIDE_OF_ROOT: &str = "Path is outside of host root";
+
+/// What to do with symlinks inside of host directory
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SymlinkPolicy {
+    /// Any symlink in path is error
+    Deny,
+    /// Symlinks can point only inside of host directory
+    #[default]
+    AllowWithinRoot,
+    /// Symlinks can point anywhere
+    AllowAll,
+}
+
+impl fmt::Display for SymlinkPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SymlinkPolicy::Deny => write!(f, "deny"),
+            SymlinkPolicy::AllowWithinRoot => write!(f, "allow-within-root"),
+            SymlinkPolicy::AllowAll => write!(f, "allow-all"),
+        }
+    }
+}
+
+fn outside_of_root(locator: &str) -> Error {
+    warn!("Locator {} is resolved outside of host root", locator);
+    Error::new_not_found(format!("{}: {}", OUTSIDE_OF_ROOT, locator).as_str())
+}
+
+/// Checks every part of path after host root, root itself can be symlink
+fn contains_symlink(host_root: &Path, relative: &Path) -> Result<bool> {
+    let mut path = host_root.to_path_buf();
+
+    for component in relative.components() {
+        path.push(component);
+
+        if path.symlink_metadata()?.file_type().is_symlink() {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Resolves locator to canonical path inside of host directory.
+/// Every file that is served or executed should be resolved here
+pub fn resolve_path(host_root: &Path, locator: &str, policy: SymlinkPolicy) -> Result<PathBuf> {
+    let relative = Path::new(locator.trim_start_matches('/'));
+
+    // Url parsing removes "..", but path shouldn't rely on that
+    if relative
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(outside_of_root(locator));
+    }
+
+    if policy == SymlinkPolicy::Deny && contains_symlink(host_root, relative)? {
+        warn!("Symlink in {} is denied", locator);
+        return Err(Error::new_not_found(
+            format!("Symlinks aren't allowed: {}", locator).as_str(),
+        ));
+    }
+
+    // Missing host root is server's problem, missing file is client's one
+    let root = host_root.canonicalize().map_err(|e| {
+        Error::new_io(format!("Host root {}: {}", host_root.to_string_lossy(), e).as_str())
+    })?;
+    let path = root
+        .join(relative)
+        .canonicalize()
+        .map_err(|e| Error::new(Error::from(e).kind(), locator))?;
+
+    if policy != SymlinkPolicy::AllowAll && !path.starts_with(&root) {
+        return Err(outside_of_root(locator));
+    }
+
+    Ok(path)
+}
+
+/// Checks resolved path relative to host root, so symlink with usual name
+/// can't expose hidden file
+fn is_hidden_target(host: &HostSettings, path: &Path) -> bool {
+    let relative = match host
+        .root
+        .canonicalize()
+        .ok()
+        .and_then(|root| path.strip_prefix(root).ok().map(Path::to_path_buf))
+    {
+        Some(relative) => relative,
+        // Target outside of root(allowed by policy) isn't covered by host patterns
+        None => return false,
+    };
+
+    let suffix = if path.is_dir() { "/" } else { "" };
+    let locator = format!("/{}{}", relative.to_string_lossy(), suffix);
+
+    is_hidden_locator(locator.as_str(), host.hide_dotfiles, &host.hidden_files)
+}
+
+/// Resolves locator inside of host directory with its symlink policy
+pub fn resolve_locator(host: &HostSettings, locator: &str) -> Result<PathBuf> {
+    let path = resolve_path(&host.root, locator, host.symlink_policy)?;
+
+    if is_hidden_target(host, &path) {
+        warn!("Locator {} is resolved to hidden file", locator);
+        return Err(Error::new_not_found(
+            format!("Hidden file behind {}", locator).as_str(),
+        ));
+    }
+
+    Ok(path)
+}
+
+/// Resolves .listfiles of directory locator. It's hidden itself,
+/// so only file behind symlink is checked
+pub fn resolve_listfiles(host: &HostSettings, directory: &Path, locator: &str) -> Result<PathBuf> {
+    let listfiles = format!("{}.listfiles", locator);
+    let path = resolve_path(&host.root, listfiles.as_str(), host.symlink_policy)?;
+
+    if path != directory.join(".listfiles") && is_hidden_target(host, &path) {
+        warn!("Listfiles {} is resolved to hidden file", listfiles);
+        return Err(Error::new_not_found(
+            format!("Hidden file behind {}", listfiles).as_str(),
+        ));
+    }
+
+    Ok(path)
+}
+
+// ----------------- Tests section --------------------
+
+/// Host directory with "file.gmi", symlink to it and symlink to file outside of host
+#[cfg(test)]
+fn symlinks_test_root() -> (tempfile::TempDir, PathBuf) {
+    use std::os::unix::fs::symlink;
+
+    let dir = tempfile::tempdir().unwrap();
+    let host_root = dir.path().join("localhost");
+    let secret = dir.path().join("secret.txt");
+
+    std::fs::create_dir_all(host_root.join("docs")).unwrap();
+    std::fs::write(host_root.join("docs/file.gmi"), "# File").unwrap();
+    std::fs::write(&secret, "secret").unwrap();
+    symlink(host_root.join("docs"), host_root.join("inner")).unwrap();
+    symlink(&secret, host_root.join("escape.txt")).unwrap();
+
+    (dir, host_root)
+}
+
+#[test]
+fn resolve_path_plain_file() {
+    let (_dir, root) = symlinks_test_root();
+    let expected = root.join("docs/file.gmi").canonicalize().unwrap();
+
+    for policy in [
+        SymlinkPolicy::Deny,
+        SymlinkPolicy::AllowWithinRoot,
+        SymlinkPolicy::AllowAll,
+    ] {
+        assert_eq!(
+            resolve_path(&root, "/docs/file.gmi", policy),
+            Ok(expected.clone())
+        );
+    }
+
+    assert_eq!(
+        resolve_path(&root, "/docs/missing.gmi", SymlinkPolicy::AllowAll),
+        Err(Error::new_not_found("/docs/missing.gmi"))
+    );
+    assert!(resolve_path(&root, "/docs/../../secret.txt", SymlinkPolicy::AllowAll).is_err());
+}
+
+#[test]
+fn resolve_path_deny_symlinks() {
+    let (_dir, root) = symlinks_test_root();
+
+    assert!(resolve_path(&root, "/inner/file.gmi", SymlinkPolicy::Deny).is_err());
+    assert!(resolve_path(&root, "/escape.txt", SymlinkPolicy::Deny).is_err());
+}
+
+#[test]
+fn resolve_path_allow_symlinks_within_root() {
+    let (_dir, root) = symlinks_test_root();
+
+    assert_eq!(
+        resolve_path(&root, "/inner/file.gmi", SymlinkPolicy::AllowWithinRoot),
+        Ok(root.join("docs/file.gmi").canonicalize().unwrap())
+    );
+    assert!(resolve_path(&root, "/escape.txt", SymlinkPolicy::AllowWithinRoot).is_err());
+}
+
+#[test]
+fn resolve_path_allow_all_symlinks() {
+    let (dir, root) = symlinks_test_root();
+
+    assert_eq!(
+        resolve_path(&root, "/escape.txt", SymlinkPolicy::AllowAll),
+        Ok(dir.path().join("secret.txt").canonicalize().unwrap())
+    );
+}
+
+#[test]
+fn resolve_locator_hidden_target() {
+    use crate::configuration::Configuration;
+    use crate::pipe::vhost::VirtualHost;
+    use std::os::unix::fs::symlink;
+
+    let (_dir, root) = symlinks_test_root();
+    std::fs::write(root.join(".env"), "SECRET=1").unwrap();
+    std::fs::write(root.join("docs/old.bak"), "old").unwrap();
+    symlink(root.join(".env"), root.join("notes.txt")).unwrap();
+    symlink(root.join("docs/old.bak"), root.join("docs/current.gmi")).unwrap();
+
+    let host = HostSettings::new(
+        &Configuration::default(),
+        VirtualHost::new("localhost", root.clone()),
+    );
+
+    assert!(resolve_locator(&host, "/notes.txt").is_err());
+    assert!(resolve_locator(&host, "/docs/current.gmi").is_err());
+    assert!(resolve_locator(&host, "/inner/file.gmi").is_ok());
+    assert!(resolve_locator(&host, "/").is_ok());
+}
+
+#[test]
+fn resolve_listfiles_with_policy() {
+    use crate::configuration::Configuration;
+    use crate::pipe::vhost::VirtualHost;
+    use std::os::unix::fs::symlink;
+
+    let (dir, root) = symlinks_test_root();
+    std::fs::write(root.join("docs/.listfiles"), "# Docs").unwrap();
+    std::fs::write(root.join("docs/old.bak"), "old").unwrap();
+    std::fs::create_dir(root.join("old")).unwrap();
+    symlink(dir.path().join("secret.txt"), root.join(".listfiles")).unwrap();
+    symlink(root.join("docs/old.bak"), root.join("old/.listfiles")).unwrap();
+
+    let host = HostSettings::new(
+        &Configuration::default(),
+        VirtualHost::new("localhost", root.clone()),
+    );
+    let resolve = |locator: &str| {
+        let directory = resolve_locator(&host, locator).unwrap();
+
+        resolve_listfiles(&host, &directory, locator)
+    };
+
+    assert_eq!(
+        resolve("/docs/"),
+        Ok(root.join("docs/.listfiles").canonicalize().unwrap())
+    );
+    assert!(resolve("/inner/").is_ok());
+    assert!(resolve("/").is_err());
+    assert!(resolve("/old/").is_err());
+}
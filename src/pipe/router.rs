@@ -1,76 +1,364 @@
 use log::debug;
-use std::path::PathBuf;
+use std::path::Path;
 
-use tokio::fs;
-
-use crate::configuration::SETTINGS;
-use crate::error::Result;
+use crate::configuration::{Configuration, SETTINGS};
+use crate::error::{Error, Result};
+use crate::pipe::cgi::{find_script, process_cgi};
 use crate::pipe::directory::process_directory;
 use crate::pipe::file::process_file;
+use crate::pipe::hidden::is_hidden_locator;
+use crate::pipe::redirect::{encode_locator, find_redirect};
+use crate::pipe::resolver::resolve_locator;
+use crate::pipe::vhost::{host_candidates, normalize_host, HostSettings, HostTable, HOST_TABLE};
 use crate::protocol::request::Request;
 use crate::protocol::response::Response;
-use crate::protocol::NOT_SERVED;
 
 fn is_directory_locator(locator: String) -> bool {
     locator.ends_with('/')
 }
 
-pub async fn get_root_dir() -> Result<PathBuf> {
-    Ok(PathBuf::from(SETTINGS.read().await.to_owned().root_path))
+/// Directory requested without slash on end(relative links will be broken for it)
+fn is_missing_trailing_slash(path: &Path, locator: &str) -> bool {
+    !is_directory_locator(locator.to_string()) && path.is_dir()
 }
 
-async fn is_host_exists(host: String) -> Result<bool> {
-    let mut path = get_root_dir().await?;
-    path.push(host.as_str());
+/// Locator is decoded, so it's encoded back before sending to client
+fn trailing_slash_location(locator: &str, query: Option<&str>) -> String {
+    let path = encode_locator(locator);
 
-    fs::read_dir(path)
-        .await
-        .map_or_else(|_| Ok(false), |_| Ok(true))
+    match query {
+        Some(query) => format!("{}/?{}", path, query),
+        None => format!("{}/", path),
+    }
 }
 
-async fn process_request(request: Request) -> Result<Response> {
-    if is_directory_locator(request.locator.clone()) {
-        process_directory(request).await
-    } else {
-        process_file(request).await
-    }
+/// Hidden files are answered like missing ones, so their existence isn't exposed
+fn hidden_error(host: &HostSettings, locator: &str) -> Option<Error> {
+    is_hidden_locator(locator, host.hide_dotfiles, &host.hidden_files)
+        .then(|| Error::new_not_found(format!("Hidden file {}", locator).as_str()))
+}
+
+/// Checks redirect table of host before touching filesystem
+fn redirect_response(host: &HostSettings, locator: &str, query: Option<&str>) -> Option<Response> {
+    find_redirect(&host.redirects, locator, query).map(Response::new_redirect)
+}
+
+/// Settings of first host candidate that has directory in table
+fn find_host(config: &Configuration, table: &HostTable, host: &str) -> Option<HostSettings> {
+    let vhost = host_candidates(config, host)
+        .into_iter()
+        .find(|vhost| table.contains(&vhost.directory))?;
+
+    debug!(
+        "Processing host: {} from {}",
+        vhost.name,
+        vhost.directory.to_string_lossy()
+    );
+
+    Some(HostSettings::new(config, vhost))
 }
 
-pub async fn route(request: Request) -> Result<Response> {
-    let host = request.host.clone();
+/// Finds host that serves request and its settings. None if host isn't served
+pub async fn resolve_host(request: &Request) -> Option<HostSettings> {
+    let config = SETTINGS.read().await;
+    let table = HOST_TABLE.read().await;
 
-    let is_any_exists = is_host_exists("any".to_string()).await?;
-    let is_required_host_exists = is_host_exists(host.clone()).await?;
+    find_host(&config, &table, request.host.as_str())
+}
+
+pub async fn route(request: Request, host: HostSettings) -> Result<Response> {
+    // Scripts should see host as client sent it, but without port and case differences
+    let mut request = request;
+    request.host = normalize_host(request.host.as_str());
 
-    if is_any_exists || is_required_host_exists {
-        let selected_host = if is_required_host_exists {
-            host
-        } else {
-            "any".to_string()
-        };
+    if let Some(response) =
+        redirect_response(&host, request.locator.as_str(), request.query.as_deref())
+    {
+        debug!(
+            "Redirecting {} to {}",
+            request.locator, response.status_line
+        );
+
+        return Ok(response);
+    }
 
-        debug!("Processing host: {}", selected_host);
+    let script = host
+        .cgi
+        .then(|| find_script(&host.root, request.locator.as_str()))
+        .flatten();
+
+    // Path info is only argument of script, so just script itself can be hidden
+    let visible = script
+        .as_ref()
+        .map_or(request.locator.as_str(), |script| script.name.as_str());
+
+    if let Some(error) = hidden_error(&host, visible) {
+        return Err(error);
+    }
 
-        let mut updated_request = request.clone();
-        updated_request.host = selected_host;
+    if let Some(mut script) = script {
+        script.path = resolve_locator(&host, script.name.as_str())?;
 
-        process_request(updated_request).await
+        return process_cgi(script, request).await;
+    }
+
+    // Resolved path, so directory behind denied symlink isn't exposed
+    if resolve_locator(&host, request.locator.as_str())
+        .is_ok_and(|path| is_missing_trailing_slash(&path, request.locator.as_str()))
+    {
+        debug!("Directory without trailing slash: {}", request.locator);
+
+        return Ok(Response::new_redirect(trailing_slash_location(
+            request.locator.as_str(),
+            request.query.as_deref(),
+        )));
+    }
+
+    if is_directory_locator(request.locator.clone()) {
+        process_directory(request, &host).await
     } else {
-        Ok(Response::new_server_error(NOT_SERVED.to_string()))
+        process_file(request, &host).await
     }
 }
 
 #[test]
 fn is_directory_locator_test() {
-    assert_eq!(is_directory_locator("/".to_string()), true);
-    assert_eq!(is_directory_locator("/some path/".to_string()), true);
+    assert!(is_directory_locator("/".to_string()));
+    assert!(is_directory_locator("/some path/".to_string()));
+    assert!(is_directory_locator(
+        "/nested/path/to/directory/".to_string()
+    ));
+    assert!(!is_directory_locator("/index.gmi".to_string()));
+    assert!(!is_directory_locator("/long/path/to/file".to_string()));
+}
+
+#[test]
+fn is_missing_trailing_slash_test() {
+    let files = Path::new("./example/localhost/files");
+    let license = Path::new("./example/localhost/files/LICENSE");
+    let missing = Path::new("./example/localhost/not-exists");
+
+    assert!(is_missing_trailing_slash(files, "/files"));
+    assert!(!is_missing_trailing_slash(files, "/files/"));
+    assert!(!is_missing_trailing_slash(license, "/files/LICENSE"));
+    assert!(!is_missing_trailing_slash(missing, "/not-exists"));
+}
+
+#[test]
+fn trailing_slash_location_test() {
+    assert_eq!(trailing_slash_location("/files", None), "/files/");
+    assert_eq!(trailing_slash_location("/my dir", None), "/my%20dir/");
+    assert_eq!(
+        trailing_slash_location("/docs/100%", Some("page=2")),
+        "/docs/100%25/?page=2"
+    );
+}
+
+#[cfg(test)]
+fn redirect_test_config() -> Configuration {
+    crate::configuration::hosts_test_config(
+        r#"
+            [[hosts."example.com".redirects]]
+            kind = "exact"
+            from = "/old.gmi"
+            to = "/new.gmi"
+
+            [[hosts."example.com".redirects]]
+            kind = "prefix"
+            from = "/docs/"
+            to = "/files/"
+
+            [[hosts."example.com".redirects]]
+            kind = "regex"
+            from = "^/users/([a-z]+)/?$"
+            to = "/~$1/"
+            "#,
+    )
+}
+
+#[cfg(test)]
+fn redirect_test_host(name: &str) -> HostSettings {
+    use crate::pipe::vhost::VirtualHost;
+
+    let vhost = VirtualHost::new(name, Path::new("./example").join(name));
+
+    HostSettings::new(&redirect_test_config(), vhost)
+}
+
+#[test]
+fn redirect_response_exact() {
+    let host = redirect_test_host("example.com");
+
     assert_eq!(
-        is_directory_locator("/nested/path/to/directory/".to_string()),
-        true
+        redirect_response(&host, "/old.gmi", None),
+        Some(Response::new_redirect("/new.gmi".to_string()))
     );
-    assert_eq!(is_directory_locator("/index.gmi".to_string()), false);
+    assert_eq!(redirect_response(&host, "/old.gmi2", None), None);
+}
+
+#[test]
+fn redirect_response_prefix() {
+    let host = redirect_test_host("example.com");
+
     assert_eq!(
-        is_directory_locator("/long/path/to/file".to_string()),
-        false
+        redirect_response(&host, "/docs/manual/index.gmi", None),
+        Some(Response::new_redirect(
+            "/files/manual/index.gmi".to_string()
+        ))
     );
+    assert_eq!(redirect_response(&host, "/docs", None), None);
+}
+
+#[test]
+fn redirect_response_encodes_locator() {
+    let host = redirect_test_host("example.com");
+
+    // "/docs/x%0D%0A2 text/gemini" after decoding can't break status line
+    assert_eq!(
+        redirect_response(&host, "/docs/x\r\n2 text/gemini", None),
+        Some(Response::new_redirect(
+            "/files/x%0D%0A2%20text/gemini".to_string()
+        ))
+    );
+    assert_eq!(
+        redirect_response(&host, "/docs/my notes.gmi", Some("page=2")),
+        Some(Response::new_redirect(
+            "/files/my%20notes.gmi?page=2".to_string()
+        ))
+    );
+    assert_eq!(
+        redirect_response(&host, "/old.gmi", Some("page=2")),
+        Some(Response::new_redirect("/new.gmi?page=2".to_string()))
+    );
+}
+
+#[test]
+fn redirect_response_regex() {
+    let host = redirect_test_host("example.com");
+
+    assert_eq!(
+        redirect_response(&host, "/users/alice", None),
+        Some(Response::new_redirect("/~alice/".to_string()))
+    );
+    assert_eq!(redirect_response(&host, "/users/Alice", None), None);
+}
+
+#[test]
+fn redirect_response_other_host() {
+    let host = redirect_test_host("localhost");
+
+    assert_eq!(redirect_response(&host, "/old.gmi", None), None);
+}
+
+#[test]
+fn hidden_error_for_dotfiles_and_patterns() {
+    use crate::protocol::NOT_FOUND;
+
+    let host = redirect_test_host("localhost");
+    let not_found = Some(Response::new_client_error(NOT_FOUND.to_string()));
+    let response = |locator| hidden_error(&host, locator).map(|e| Response::from_error(&e));
+
+    assert_eq!(response("/.listfiles"), not_found);
+    assert_eq!(response("/files/.git/config"), not_found);
+    assert_eq!(response("/index.gmi~"), not_found);
+    assert_eq!(response("/files/index.gmi.bak"), not_found);
+    assert_eq!(response("/files/index.gmi"), None);
+}
+
+#[tokio::test]
+async fn hidden_check_skips_path_info() {
+    use crate::error::ErrorKind;
+    use crate::pipe::vhost::VirtualHost;
+    use crate::protocol::response::StatusCode;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = tempfile::tempdir().unwrap();
+    for name in ["app.cgi", ".app.cgi"] {
+        let script = root.path().join(name);
+
+        fs::write(&script, "#!/bin/sh\necho '2 text/gemini'\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    let vhost = VirtualHost::new("localhost", root.path().to_path_buf());
+    let mut host = HostSettings::new(&redirect_test_config(), vhost);
+    host.cgi = true;
+
+    let request = |line: &str| Request::create_from_request_line(line.to_string()).unwrap();
+
+    for line in [
+        "localhost /app.cgi/.well-known/x 0",
+        "localhost /app.cgi/export.bak 0",
+    ] {
+        let response = route(request(line), host.clone()).await.unwrap();
+
+        assert_eq!(response.status_code, StatusCode::Success);
+    }
+
+    let error = route(request("localhost /.app.cgi/x 0"), host.clone())
+        .await
+        .unwrap_err();
+
+    assert_eq!(error.kind(), ErrorKind::NotFound);
+}
+
+#[tokio::test]
+async fn wildcard_settings_for_any_host_name() {
+    use crate::error::ErrorKind;
+    use crate::pipe::directory::DirectoryListing;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let alice = dir.path().join("users.example.com/alice");
+    let script = alice.join("app.cgi");
+
+    fs::create_dir_all(&alice).unwrap();
+    fs::create_dir_all(dir.path().join("any")).unwrap();
+    fs::write(&script, "#!/bin/sh\necho '2 text/gemini'\n").unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let config = Configuration {
+        root_path: dir.path().to_string_lossy().to_string(),
+        ..crate::configuration::hosts_test_config(
+            r#"
+            [hosts."*.users.example.com"]
+            cgi = false
+            max_upload_size = 0
+            directory_listing = "never"
+            "#,
+        )
+    };
+    let table = HostTable::build(&config);
+
+    // Every name that reaches directory of subdomain gets settings of wildcard
+    for name in [
+        "alice.users.example.com",
+        "Alice.Users.Example.com.:300",
+        "users.example.com",
+        "alice",
+    ] {
+        let host = find_host(&config, &table, name).unwrap();
+
+        if alice.starts_with(&host.root) || host.root.starts_with(&alice) {
+            assert_eq!(host.name, "*.users.example.com");
+            assert!(!host.cgi);
+            assert_eq!(host.max_upload_size, 0);
+            assert_eq!(host.directory_listing, DirectoryListing::Never);
+        }
+    }
+
+    assert_eq!(
+        find_host(&config, &table, "alice.users.example.com").map(|host| host.root),
+        Some(alice)
+    );
+
+    let request = |line: &str| Request::create_from_request_line(line.to_string()).unwrap();
+    let host = find_host(&config, &table, "users.example.com").unwrap();
+    let error = route(request("users.example.com /alice/app.cgi 0"), host)
+        .await
+        .unwrap_err();
+
+    assert_eq!(error.kind(), ErrorKind::NotFound);
 }
@@ -0,0 +1,507 @@
+use crate::configuration::Configuration;
+use crate::error::{Error, Result};
+use crate::mime::with_parameters;
+use crate::pipe::directory::DirectoryListing;
+use crate::pipe::error_page::ErrorPages;
+use crate::pipe::hidden::HiddenPattern;
+use crate::pipe::redirect::Redirect;
+use crate::pipe::resolver::SymlinkPolicy;
+
+use lazy_static::lazy_static;
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tokio::sync::RwLock;
+
+/// Fallback host that serves requests for hosts without own directory
+pub const ANY_HOST: &str = "any";
+
+const WILDCARD_PREFIX: &str = "*.";
+
+/// Virtual host that serves request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VirtualHost {
+    /// Name in hosts configuration(directory name, aliased host or wildcard)
+    pub name: String,
+    /// Directory with files of host
+    pub directory: PathBuf,
+}
+
+impl VirtualHost {
+    pub fn new(name: &str, directory: PathBuf) -> Self {
+        Self {
+            name: name.to_string(),
+            directory,
+        }
+    }
+}
+
+/// "EXAMPLE.com.:300" is "example.com", IPv6 addresses keep brackets: "[::1]:300" is "[::1]"
+pub fn normalize_host(host: &str) -> String {
+    let without_port = if host.starts_with('[') {
+        host.split_inclusive(']').next().unwrap_or(host)
+    } else {
+        match host.rsplit_once(':') {
+            Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+            _ => host,
+        }
+    };
+
+    without_port.trim_end_matches('.').to_lowercase()
+}
+
+/// Host is used as directory name, so it can't lead outside of server root
+fn is_valid_host(host: &str) -> bool {
+    !host.is_empty() && host != "." && host != ".." && !host.contains(['/', '\\', '\0'])
+}
+
+/// "alice.users.example.com" matches "*.users.example.com" and served from "users.example.com/alice"
+fn wildcard_directory(pattern: &str, host: &str) -> Option<PathBuf> {
+    let domain = pattern.strip_prefix(WILDCARD_PREFIX)?;
+    let label = host.strip_suffix(domain)?.strip_suffix('.')?;
+
+    (is_valid_host(label) && !label.contains('.')).then(|| PathBuf::from(domain).join(label))
+}
+
+/// Host directory is inside of server root, if it isn't set for host explicitly.
+/// Wildcard hosts have subdirectory for every subdomain
+fn host_directory(config: &Configuration, name: &str, directory: &Path) -> PathBuf {
+    let root = config
+        .host_configuration(name)
+        .and_then(|c| c.root.as_ref());
+
+    match (root, name.strip_prefix(WILDCARD_PREFIX)) {
+        (Some(root), Some(domain)) => {
+            Path::new(root).join(directory.strip_prefix(domain).unwrap_or(directory))
+        }
+        (Some(root), None) => PathBuf::from(root),
+        (None, _) => Path::new(config.root_path.as_str()).join(directory),
+    }
+}
+
+/// Hosts that can serve request in priority order: aliases, exact directory, wildcards and "any".
+/// Router takes first one that exists
+pub fn host_candidates(config: &Configuration, host: &str) -> Vec<VirtualHost> {
+    let host = normalize_host(host);
+    let mut candidates = Vec::new();
+
+    if !is_valid_host(host.as_str()) {
+        candidates.push(VirtualHost::new(ANY_HOST, PathBuf::from(ANY_HOST)));
+        return absolute_candidates(config, candidates);
+    }
+
+    // Sorted, so result doesn't depend on HashMap order
+    let mut names: Vec<&String> = config.hosts.keys().collect();
+    names.sort();
+
+    for name in names.iter() {
+        let aliases = &config.hosts[*name].aliases;
+
+        if aliases.iter().any(|a| normalize_host(a) == host) && is_valid_host(name) {
+            candidates.push(VirtualHost::new(name, PathBuf::from(name.as_str())));
+        }
+    }
+
+    candidates.push(VirtualHost::new(&host, PathBuf::from(host.as_str())));
+
+    // Longer wildcard is more exact
+    names.sort_by_key(|name| std::cmp::Reverse(name.len()));
+
+    for name in names {
+        if let Some(directory) = wildcard_directory(name, host.as_str()) {
+            candidates.push(VirtualHost::new(name, directory));
+        }
+    }
+
+    candidates.push(VirtualHost::new(ANY_HOST, PathBuf::from(ANY_HOST)));
+
+    absolute_candidates(config, candidates)
+}
+
+fn absolute_candidates(config: &Configuration, candidates: Vec<VirtualHost>) -> Vec<VirtualHost> {
+    candidates
+        .into_iter()
+        .map(|vhost| VirtualHost {
+            directory: host_directory(config, vhost.name.as_str(), &vhost.directory),
+            name: vhost.name,
+        })
+        .collect()
+}
+
+/// Existing host directories, so routing doesn't touch filesystem.
+/// New directories are visible after configuration reload
+#[derive(Clone, Debug, Default)]
+pub struct HostTable {
+    directories: HashSet<PathBuf>,
+}
+
+/// Every subdirectory can be host(or subdomain of wildcard host)
+fn add_subdirectories(directories: &mut HashSet<PathBuf>, path: &Path) {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Can't read hosts from {}: {}", path.to_string_lossy(), e);
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        // Host directory can be symlink
+        if entry.path().is_dir() {
+            directories.insert(path.join(entry.file_name()));
+        }
+    }
+}
+
+impl HostTable {
+    pub fn build(config: &Configuration) -> Self {
+        let root = Path::new(config.root_path.as_str());
+        let mut directories = HashSet::new();
+
+        let mut containers = Vec::new();
+
+        add_subdirectories(&mut directories, root);
+
+        for (name, host_config) in config.hosts.iter() {
+            let wildcard = name.strip_prefix(WILDCARD_PREFIX);
+
+            match (&host_config.root, wildcard) {
+                (Some(host_root), Some(_)) => containers.push(PathBuf::from(host_root)),
+                (Some(host_root), None) => {
+                    if Path::new(host_root).is_dir() {
+                        directories.insert(PathBuf::from(host_root));
+                    }
+                }
+                (None, Some(domain)) => containers.push(root.join(domain)),
+                // Already added with server root
+                (None, None) => {}
+            }
+        }
+
+        // Directory with subdomains isn't host itself, otherwise "users.example.com/alice/"
+        // would be served without settings of "*.users.example.com"
+        for container in containers {
+            if directories.remove(&container) {
+                warn!(
+                    "Directory {} contains subdomains of wildcard host, it isn't served as host",
+                    container.to_string_lossy()
+                );
+            }
+
+            add_subdirectories(&mut directories, &container);
+        }
+
+        Self { directories }
+    }
+
+    pub fn contains(&self, directory: &Path) -> bool {
+        self.directories.contains(directory)
+    }
+
+    pub fn len(&self) -> usize {
+        self.directories.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.directories.is_empty()
+    }
+}
+
+lazy_static! {
+    /// Filled on start and on every configuration reload
+    pub static ref HOST_TABLE: RwLock<HostTable> = RwLock::new(HostTable::default());
+}
+
+/// Reads host directories in blocking thread, so connections aren't stalled meanwhile
+pub async fn build_host_table(config: &Configuration) -> Result<HostTable> {
+    let config = config.clone();
+    let table = tokio::task::spawn_blocking(move || HostTable::build(&config))
+        .await
+        .map_err(|e| {
+            Error::new_unexpected(format!("Can't read host directories: {}", e).as_str())
+        })?;
+
+    info!("Host table built: {} host directories", table.len());
+
+    Ok(table)
+}
+
+/// Reads host directories again and swaps table
+pub async fn refresh_host_table(config: &Configuration) -> Result<()> {
+    let table = build_host_table(config).await?;
+
+    *HOST_TABLE.write().await = table;
+
+    Ok(())
+}
+
+/// Settings of virtual host with global ones applied. They're resolved once per request,
+/// so request is served with same settings even if configuration is reloaded meanwhile
+#[derive(Clone, Debug)]
+pub struct HostSettings {
+    /// Name in hosts configuration
+    pub name: String,
+    /// Directory with files of host
+    pub root: PathBuf,
+    pub max_upload_size: usize,
+    pub cgi: bool,
+    pub directory_listing: DirectoryListing,
+    pub charset: Option<String>,
+    pub lang: Option<String>,
+    pub redirects: Vec<Redirect>,
+    pub symlink_policy: SymlinkPolicy,
+    pub hide_dotfiles: bool,
+    pub hidden_files: Vec<HiddenPattern>,
+    pub mime_types: HashMap<String, String>,
+    pub errors: ErrorPages,
+}
+
+impl HostSettings {
+    pub fn new(config: &Configuration, vhost: VirtualHost) -> Self {
+        let host_config = config
+            .host_configuration(vhost.name.as_str())
+            .cloned()
+            .unwrap_or_default();
+
+        Self {
+            name: vhost.name,
+            root: vhost.directory,
+            max_upload_size: host_config
+                .max_upload_size
+                .unwrap_or(config.max_upload_size),
+            cgi: host_config.cgi.unwrap_or(config.cgi),
+            directory_listing: host_config
+                .directory_listing
+                .unwrap_or(config.directory_listing),
+            charset: host_config.charset.or_else(|| config.charset.clone()),
+            lang: host_config.lang,
+            redirects: host_config.redirects,
+            symlink_policy: config.symlink_policy,
+            hide_dotfiles: config.hide_dotfiles,
+            hidden_files: config.hidden_files.clone(),
+            mime_types: config.mime_types.clone(),
+            errors: host_config.errors.or(&config.errors),
+        }
+    }
+
+    /// Appends charset and lang of host to text types
+    pub fn text_mime(&self, mime: String) -> String {
+        with_parameters(mime, self.charset.as_deref(), self.lang.as_deref())
+    }
+}
+
+// ----------------- Tests section --------------------
+
+#[test]
+fn normalize_host_test() {
+    assert_eq!(normalize_host("example.com"), "example.com");
+    assert_eq!(normalize_host("EXAMPLE.com"), "example.com");
+    assert_eq!(normalize_host("example.com:300"), "example.com");
+    assert_eq!(normalize_host("example.com."), "example.com");
+    assert_eq!(normalize_host("Example.COM.:300"), "example.com");
+    assert_eq!(normalize_host("[::1]:300"), "[::1]");
+    assert_eq!(normalize_host("127.0.0.1:300"), "127.0.0.1");
+}
+
+#[cfg(test)]
+fn vhost_test_config() -> Configuration {
+    crate::configuration::hosts_test_config(
+        r#"
+            [hosts."example.com"]
+            aliases = ["WWW.example.com", "example.org"]
+
+            [hosts."*.users.example.com"]
+
+            [hosts."*.example.com"]
+
+            [hosts."*.people.example.com"]
+            root = "/home/people"
+            charset = "utf-8"
+
+            [hosts."static.example.com"]
+            root = "/srv/static"
+            cgi = false
+            max_upload_size = 0
+            directory_listing = "always"
+
+            [hosts."static.example.com".errors]
+            not_found = "No such file on static.example.com"
+            "#,
+    )
+}
+
+#[cfg(test)]
+fn test_vhost(name: &str, directory: &str) -> VirtualHost {
+    VirtualHost::new(name, Path::new("./").join(directory))
+}
+
+#[test]
+fn host_candidates_exact_and_alias() {
+    let config = vhost_test_config();
+    let any = test_vhost(ANY_HOST, ANY_HOST);
+    let example = test_vhost("example.com", "example.com");
+
+    assert_eq!(
+        host_candidates(&config, "Example.com:300"),
+        vec![example.clone(), any.clone()]
+    );
+    assert_eq!(
+        host_candidates(&config, "WWW.example.com."),
+        vec![
+            example,
+            test_vhost("www.example.com", "www.example.com"),
+            test_vhost("*.example.com", "example.com/www"),
+            any
+        ]
+    );
+}
+
+#[test]
+fn host_candidates_wildcard() {
+    let config = vhost_test_config();
+
+    assert_eq!(
+        host_candidates(&config, "alice.users.example.com")[1..],
+        vec![
+            test_vhost("*.users.example.com", "users.example.com/alice"),
+            test_vhost(ANY_HOST, ANY_HOST),
+        ]
+    );
+    // Only one label is matched
+    assert_eq!(host_candidates(&config, "a.b.users.example.com").len(), 2);
+    assert_eq!(host_candidates(&config, "users.example.com").len(), 3);
+}
+
+#[test]
+fn host_candidates_invalid_host() {
+    let config = vhost_test_config();
+
+    assert_eq!(
+        host_candidates(&config, ".."),
+        vec![test_vhost(ANY_HOST, ANY_HOST)]
+    );
+}
+
+#[test]
+fn host_candidates_with_own_root() {
+    let config = vhost_test_config();
+
+    assert_eq!(
+        host_candidates(&config, "static.example.com")[0],
+        VirtualHost::new("static.example.com", PathBuf::from("/srv/static"))
+    );
+    assert_eq!(
+        host_candidates(&config, "bob.people.example.com")[1],
+        VirtualHost::new("*.people.example.com", PathBuf::from("/home/people/bob"))
+    );
+}
+
+#[test]
+fn host_settings_overrides() {
+    let config = Configuration {
+        charset: Some("koi8-r".to_string()),
+        errors: ErrorPages {
+            not_found: Some("Not found anywhere".to_string()),
+            server_error: Some("Server is broken".to_string()),
+            ..ErrorPages::default()
+        },
+        ..vhost_test_config()
+    };
+
+    let host = HostSettings::new(
+        &config,
+        host_candidates(&config, "static.example.com").remove(0),
+    );
+
+    assert_eq!(host.root, PathBuf::from("/srv/static"));
+    assert!(!host.cgi);
+    assert_eq!(host.max_upload_size, 0);
+    assert_eq!(host.directory_listing, DirectoryListing::Always);
+    assert_eq!(
+        host.text_mime("text/gemini".to_string()),
+        "text/gemini; charset=koi8-r"
+    );
+    assert_eq!(
+        host.errors.not_found.as_deref(),
+        Some("No such file on static.example.com")
+    );
+    assert_eq!(
+        host.errors.server_error.as_deref(),
+        Some("Server is broken")
+    );
+
+    let host = HostSettings::new(
+        &config,
+        host_candidates(&config, "bob.people.example.com").remove(1),
+    );
+
+    assert!(host.cgi);
+    assert_eq!(host.max_upload_size, config.max_upload_size);
+    assert_eq!(host.directory_listing, DirectoryListing::Listfiles);
+    assert_eq!(
+        host.text_mime("text/gemini".to_string()),
+        "text/gemini; charset=utf-8"
+    );
+    assert_eq!(host.errors, config.errors);
+}
+
+#[test]
+fn host_table_build() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("root");
+    let own_root = dir.path().join("own");
+
+    for path in [
+        "root/example.com",
+        "root/any",
+        "root/users.example.com/alice",
+        "people/bob",
+        "own",
+    ] {
+        std::fs::create_dir_all(dir.path().join(path)).unwrap();
+    }
+    std::fs::write(root.join("file.gmi"), "# File").unwrap();
+
+    let config = Configuration {
+        root_path: root.to_string_lossy().to_string(),
+        ..crate::configuration::hosts_test_config(
+            format!(
+                r#"
+                [hosts."*.users.example.com"]
+
+                [hosts."*.people.example.com"]
+                root = "{}"
+
+                [hosts."own.example.com"]
+                root = "{}"
+                "#,
+                dir.path().join("people").to_string_lossy(),
+                own_root.to_string_lossy()
+            )
+            .as_str(),
+        )
+    };
+    let table = HostTable::build(&config);
+
+    let exists = |host: &str| {
+        host_candidates(&config, host)
+            .into_iter()
+            .find(|vhost| table.contains(&vhost.directory))
+            .map(|vhost| vhost.directory)
+    };
+
+    assert_eq!(exists("example.com"), Some(root.join("example.com")));
+    assert_eq!(exists("file.gmi"), Some(root.join("any")));
+    assert_eq!(
+        exists("alice.users.example.com"),
+        Some(root.join("users.example.com/alice"))
+    );
+    assert_eq!(
+        exists("bob.people.example.com"),
+        Some(dir.path().join("people/bob"))
+    );
+    assert_eq!(exists("own.example.com"), Some(own_root));
+    // Directory with subdomains of wildcard host isn't served as host
+    assert_eq!(exists("users.example.com"), Some(root.join("any")));
+    assert_eq!(table.len(), 5);
+}
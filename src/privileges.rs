@@ -0,0 +1,210 @@
+use crate::configuration::Configuration;
+use crate::error::{Error, Result};
+
+use log::info;
+use std::ffi::CString;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Buffer for getpwnam_r/getgrnam_r strings
+const LOOKUP_BUFFER_SIZE: usize = 16384;
+
+/// Set after chroot, since then all paths are inside new root
+static CHROOTED: AtomicBool = AtomicBool::new(false);
+
+pub fn is_chrooted() -> bool {
+    CHROOTED.load(Ordering::Relaxed)
+}
+
+fn c_string(name: &str) -> Result<CString> {
+    CString::new(name)
+        .map_err(|_| Error::new_other(format!("Wrong name {}", name.escape_debug()).as_str()))
+}
+
+/// Error for failed libc call with errno
+fn os_error(action: &str) -> Error {
+    Error::new_io(format!("{}: {}", action, io::Error::last_os_error()).as_str())
+}
+
+fn lookup_error(kind: &str, name: &str, code: libc::c_int) -> Error {
+    if code == 0 {
+        Error::new_other(format!("Unknown {} {}", kind, name).as_str())
+    } else {
+        Error::new_io(
+            format!(
+                "Can't find {} {}: {}",
+                kind,
+                name,
+                io::Error::from_raw_os_error(code)
+            )
+            .as_str(),
+        )
+    }
+}
+
+/// User id and primary group id by user name
+fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let c_name = c_string(name)?;
+    let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+    // SAFETY: passwd is plain C struct, zeroes are valid for it
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found: *mut libc::passwd = std::ptr::null_mut();
+
+    // SAFETY: all pointers are valid during call and buffer length is passed
+    let code = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+
+    if found.is_null() {
+        return Err(lookup_error("user", name, code));
+    }
+
+    Ok((passwd.pw_uid, passwd.pw_gid))
+}
+
+/// Group id by group name
+fn lookup_group(name: &str) -> Result<libc::gid_t> {
+    let c_name = c_string(name)?;
+    let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+    // SAFETY: group is plain C struct, zeroes are valid for it
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut found: *mut libc::group = std::ptr::null_mut();
+
+    // SAFETY: all pointers are valid during call and buffer length is passed
+    let code = unsafe {
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            &mut group,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+
+    if found.is_null() {
+        return Err(lookup_error("group", name, code));
+    }
+
+    Ok(group.gr_gid)
+}
+
+fn change_root(path: &str) -> Result<()> {
+    std::os::unix::fs::chroot(path)
+        .and_then(|_| std::env::set_current_dir("/"))
+        .map_err(|e| Error::new_io(format!("Can't chroot to {}: {}", path, e).as_str()))?;
+
+    CHROOTED.store(true, Ordering::Relaxed);
+    info!("Changed root to {}", path);
+
+    Ok(())
+}
+
+fn change_group(gid: libc::gid_t) -> Result<()> {
+    // SAFETY: pointer to one gid is valid during call
+    if unsafe { libc::setgroups(1, &gid) } != 0 {
+        return Err(os_error("Can't drop supplementary groups"));
+    }
+
+    // SAFETY: simple syscall without pointers
+    if unsafe { libc::setgid(gid) } != 0 {
+        return Err(os_error(format!("Can't change group to {}", gid).as_str()));
+    }
+
+    Ok(())
+}
+
+fn change_user(uid: libc::uid_t) -> Result<()> {
+    // SAFETY: simple syscall without pointers
+    if unsafe { libc::setuid(uid) } != 0 {
+        return Err(os_error(format!("Can't change user to {}", uid).as_str()));
+    }
+
+    // Root privileges shouldn't be restorable
+    // SAFETY: simple syscall without pointers
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err(Error::new_unexpected("Root privileges are still available"));
+    }
+
+    Ok(())
+}
+
+/// Applies chroot, group and user from configuration. Should be called after binding
+/// listeners, since usually only root can listen privileged port 300.
+/// Users and groups are looked up before chroot, cause /etc/passwd is usually outside of it
+pub fn drop_privileges(config: &Configuration) -> Result<()> {
+    let user = config.user.as_deref().map(lookup_user).transpose()?;
+
+    let gid = match (&config.group, user) {
+        (Some(group), _) => Some(lookup_group(group)?),
+        (None, Some((_, gid))) => Some(gid),
+        (None, None) => None,
+    };
+
+    if let Some(path) = &config.chroot {
+        change_root(path)?;
+    }
+
+    if let Some(gid) = gid {
+        change_group(gid)?;
+    }
+
+    if let Some((uid, _)) = user {
+        change_user(uid)?;
+    }
+
+    if let Some(gid) = gid {
+        info!(
+            "Running as user {} with group id {}",
+            config.user.as_deref().unwrap_or("-"),
+            gid
+        );
+    }
+
+    // Root can become unreachable after chroot or for new user
+    Path::new(config.root_path.as_str())
+        .read_dir()
+        .map(|_| ())
+        .map_err(|e| {
+            Error::new_other(
+                format!(
+                    "Server root {} isn't reachable after dropping privileges: {}",
+                    config.root_path, e
+                )
+                .as_str(),
+            )
+        })
+}
+
+// ----------------- Tests section --------------------
+
+#[test]
+fn lookup_root_user_and_group() {
+    assert_eq!(lookup_user("root"), Ok((0, 0)));
+    assert_eq!(lookup_group("root"), Ok(0));
+}
+
+#[test]
+fn lookup_unknown_user_and_group() {
+    assert!(lookup_user("rustan-not-exists").is_err());
+    assert!(lookup_group("rustan-not-exists").is_err());
+    assert!(lookup_user("bad\0name").is_err());
+}
+
+#[test]
+fn drop_privileges_without_settings() {
+    let config = Configuration::new(
+        vec!["127.0.0.1:3000".parse().unwrap()],
+        "./example".to_string(),
+        1024,
+    );
+
+    assert_eq!(drop_privileges(&config), Ok(()));
+    assert!(!is_chrooted());
+}
@@ -5,5 +5,10 @@ pub const PARSE_ERR: &str = "Can't parse request string";
 pub const EMPTY_REQ: &str = "Empty request";
 pub const WRONG_DATA_SIZE: &str = "Wrong data size";
 pub const NOT_ALLOWED: &str = "Not allowed";
+pub const NOT_FOUND: &str = "Not found";
 pub const NOT_SERVED: &str = "Host not served";
 pub const UPLOAD_TOO_BIG: &str = "Upload too big";
+pub const REQUEST_TIMEOUT: &str = "Request timeout";
+pub const REQUEST_TOO_LONG: &str = "Request line too long";
+pub const SCRIPT_ERROR: &str = "Script error";
+pub const INTERNAL_ERROR: &str = "Internal server error";
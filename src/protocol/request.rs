@@ -4,6 +4,7 @@ use crate::protocol::{EMPTY_REQ, PARSE_ERR, WRONG_DATA_SIZE};
 use bytes::Bytes;
 use log::debug;
 use std::fmt;
+use std::net::SocketAddr;
 use url::Url;
 use urlencoding::decode;
 
@@ -11,8 +12,14 @@ use urlencoding::decode;
 pub struct Request {
     pub host: String,
     pub locator: String,
+    /// Query part of locator as client sent it(still url-encoded, like CGI wants)
+    pub query: Option<String>,
     pub data_len: usize,
     pub data: Option<Bytes>,
+    /// Address of client
+    pub remote_addr: Option<SocketAddr>,
+    /// Address of listener that accepted connection
+    pub local_addr: Option<SocketAddr>,
 }
 
 impl Default for Request {
@@ -20,8 +27,11 @@ impl Default for Request {
         Request {
             host: "localhost".to_string(),
             locator: "/".to_string(),
+            query: None,
             data_len: 0,
             data: None,
+            remote_addr: None,
+            local_addr: None,
         }
     }
 }
@@ -30,8 +40,13 @@ impl fmt::Display for Request {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "{}{} with body that contains {} byte(s)",
-            self.host, self.locator, self.data_len
+            "{}{}{} with body that contains {} byte(s)",
+            self.host,
+            self.locator,
+            self.query
+                .as_ref()
+                .map_or(String::new(), |q| format!("?{}", q)),
+            self.data_len
         )
     }
 }
@@ -44,10 +59,10 @@ impl Request {
         // Request format "hostname SPACE full-locator SPACE post-data-lenght CRLF"
         // So first formal test - count of elements in request line
         if tokens.len() != 3 {
-            Err(Error::new_request_error(PARSE_ERR))
+            Err(Error::new_bad_request(PARSE_ERR))
         } else {
             let host_str = tokens
-                .get(0)
+                .first()
                 .ok_or_else(|| Error::new_unexpected("Host lost from string"))?;
 
             let locator_str = tokens
@@ -55,29 +70,35 @@ impl Request {
                 .ok_or_else(|| Error::new_unexpected("Locator lost from string"))?;
 
             let url = Url::parse(format!("spartan://{}{}", host_str, locator_str).as_str())
-                .map_err(|e| Error::new_request_error(e.to_string().as_str()))?;
+                .map_err(|e| Error::new_bad_request(e.to_string().as_str()))?;
 
             let real_path = decode(url.path().to_string().as_str())
                 .map(|s| s.to_string())
-                .map_err(|e| Error::new_request_error(e.to_string().as_str()))?;
+                .map_err(|e| Error::new_bad_request(e.to_string().as_str()))?;
+
+            // Decoded query can't be split to parameters, so it's kept encoded
+            let query = url.query().map(|q| q.to_string());
 
             let size_value: usize = tokens
                 .get(2)
                 .ok_or_else(|| Error::new_unexpected("Data len lost from string"))?
                 .to_string()
                 .parse::<usize>()
-                .map_err(|_| Error::new_request_error(PARSE_ERR))?;
+                .map_err(|_| Error::new_bad_request(PARSE_ERR))?;
 
             debug!(
-                "Decoded host: {}, path: {}, data_len: {}",
-                host_str, real_path, size_value
+                "Decoded host: {}, path: {}, query: {:?}, data_len: {}",
+                host_str, real_path, query, size_value
             );
 
             Ok(Request {
                 host: host_str.to_string(),
                 locator: real_path,
+                query,
                 data_len: size_value,
                 data: None,
+                remote_addr: None,
+                local_addr: None,
             })
         }
     }
@@ -85,7 +106,7 @@ impl Request {
     /// Creates Request structure(important! there still no request data)
     pub fn create_from_request_line(request: String) -> Result<Request> {
         let result: Result<Request> = if request.is_empty() {
-            Err(Error::new_request_error(EMPTY_REQ))
+            Err(Error::new_bad_request(EMPTY_REQ))
         } else {
             Request::try_parse_line(request)
         };
@@ -93,10 +114,23 @@ impl Request {
         result
     }
 
+    /// Remember which connection request came from
+    pub fn with_addresses(
+        self,
+        remote_addr: Option<SocketAddr>,
+        local_addr: Option<SocketAddr>,
+    ) -> Request {
+        Request {
+            remote_addr,
+            local_addr,
+            ..self
+        }
+    }
+
     /// Append data to request object
     pub fn append_data(&self, data: Bytes) -> Result<Request> {
         if data.len() != self.data_len {
-            Err(Error::new_request_error(
+            Err(Error::new_bad_request(
                 format!(
                     "{} expect {} got {}",
                     WRONG_DATA_SIZE,
@@ -123,8 +157,11 @@ fn create_from_request_path_under_root() {
     let except = Ok(Request {
         host: "my-good-host.com".to_string(),
         locator: "/etc/passwd".to_string(),
+        query: None,
         data_len: 0,
         data: None,
+        remote_addr: None,
+        local_addr: None,
     });
 
     assert!(result.is_ok());
@@ -134,7 +171,7 @@ fn create_from_request_path_under_root() {
 #[test]
 fn create_from_request_line_empty_line() {
     let result = Request::create_from_request_line("".to_string());
-    let expect = Err(Error::new_request_error(EMPTY_REQ));
+    let expect = Err(Error::new_bad_request(EMPTY_REQ));
     assert!(result.is_err());
 
     assert_eq!(result, expect);
@@ -143,7 +180,7 @@ fn create_from_request_line_empty_line() {
 #[test]
 fn create_from_request_line_wrong_data_len() {
     let result = Request::create_from_request_line("somehost /some/path not-a-number".to_string());
-    let except = Err(Error::new_request_error(PARSE_ERR));
+    let except = Err(Error::new_bad_request(PARSE_ERR));
 
     assert!(result.is_err());
     assert_eq!(except, result);
@@ -156,8 +193,11 @@ fn create_from_request_line_zero_body() {
     let except = Ok(Request {
         host: "my-good-host.com".to_string(),
         locator: "/resource test".to_string(),
+        query: None,
         data_len: 0,
         data: None,
+        remote_addr: None,
+        local_addr: None,
     });
 
     assert!(result.is_ok());
@@ -170,14 +210,60 @@ fn create_from_request_line_body_contains_size() {
     let except = Ok(Request {
         host: "host.com".to_string(),
         locator: "/addr".to_string(),
+        query: None,
         data_len: 12,
         data: None,
+        remote_addr: None,
+        local_addr: None,
     });
 
     assert!(result.is_ok());
     assert_eq!(result, except);
 }
 
+#[test]
+fn create_from_request_line_with_query() {
+    let result = Request::create_from_request_line("host.com /search?rust 0".to_string());
+    let except = Ok(Request {
+        host: "host.com".to_string(),
+        locator: "/search".to_string(),
+        query: Some("rust".to_string()),
+        data_len: 0,
+        data: None,
+        remote_addr: None,
+        local_addr: None,
+    });
+
+    assert!(result.is_ok());
+    assert_eq!(result, except);
+}
+
+#[test]
+fn create_from_request_line_encoded_query() {
+    let result =
+        Request::create_from_request_line("host.com /search%20page?hello%20world 0".to_string());
+
+    assert_eq!(
+        result.as_ref().map(|r| r.locator.as_str()),
+        Ok("/search page")
+    );
+    assert_eq!(
+        result.as_ref().map(|r| r.query.clone()),
+        Ok(Some("hello%20world".to_string()))
+    );
+
+    let result = Request::create_from_request_line("host.com /search?a%26b=c 0".to_string());
+
+    assert_eq!(result.map(|r| r.query), Ok(Some("a%26b=c".to_string())));
+}
+
+#[test]
+fn create_from_request_line_without_query() {
+    let result = Request::create_from_request_line("host.com /search 0".to_string());
+
+    assert_eq!(result.map(|r| r.query), Ok(None));
+}
+
 #[test]
 fn append_data_empty_data_but_data_len_is_set() {
     let result = Request::create_from_request_line("host /addr 12".to_string())
@@ -196,8 +282,11 @@ fn append_data_right_size() {
     let except = Ok(Request {
         host: "host".to_string(),
         locator: "/addr".to_string(),
+        query: None,
         data_len: 12,
         data: Some(byte_data.clone()),
+        remote_addr: None,
+        local_addr: None,
     });
 
     assert!(result.is_ok());
@@ -1,8 +1,10 @@
+use crate::error::{Error, ErrorKind};
+use crate::protocol::{INTERNAL_ERROR, NOT_ALLOWED, NOT_FOUND, REQUEST_TIMEOUT, SCRIPT_ERROR};
 use bytes::Bytes;
-use int_enum::IntEnum;
 use std::fmt;
+use std::path::PathBuf;
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, IntEnum)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(u8)]
 pub enum StatusCode {
     Success = 2,
@@ -24,11 +26,28 @@ impl StatusCode {
 
 pub const UNKNOWN_ERROR: &str = "Unknown error";
 
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Body {
+    /// Small generated content that already stored in memory
+    Buffered(Bytes),
+    /// File that will be copied to socket by chunks
+    File(PathBuf),
+}
+
+impl fmt::Display for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Body::Buffered(content) => write!(f, "{}", content.len()),
+            Body::File(path) => write!(f, "streamed from {}", path.to_string_lossy()),
+        }
+    }
+}
+
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub struct Response {
     pub status_code: StatusCode,
     pub status_line: String,
-    pub content: Option<Bytes>,
+    pub content: Option<Body>,
 }
 
 impl Default for Response {
@@ -48,13 +67,15 @@ impl fmt::Display for Response {
             "{:?} {:?} content lenght: {}",
             self.status_code,
             self.status_line,
-            self.content.to_owned().map_or(0, |c| { c.len() })
+            self.content
+                .as_ref()
+                .map_or("0".to_string(), |c| c.to_string())
         )
     }
 }
 
 impl Response {
-    pub fn new(status_code: StatusCode, status_line: String, content: Option<Bytes>) -> Response {
+    pub fn new(status_code: StatusCode, status_line: String, content: Option<Body>) -> Response {
         Response {
             status_code,
             status_line,
@@ -63,7 +84,15 @@ impl Response {
     }
 
     pub fn new_success(content_type: String, content: Bytes) -> Response {
-        Response::new(StatusCode::Success, content_type, Some(content))
+        Response::new(
+            StatusCode::Success,
+            content_type,
+            Some(Body::Buffered(content)),
+        )
+    }
+
+    pub fn new_file(content_type: String, path: PathBuf) -> Response {
+        Response::new(StatusCode::Success, content_type, Some(Body::File(path)))
     }
 
     pub fn new_client_error(error: String) -> Response {
@@ -78,8 +107,24 @@ impl Response {
         Response::new(StatusCode::Redirect, location, None)
     }
 
+    /// The only place where errors become responses. Only request and timeout messages
+    /// are written for client, others can contain paths and go to logs only
+    pub fn from_error(error: &Error) -> Response {
+        match error.kind() {
+            ErrorKind::BadRequest => Response::new_client_error(error.message().to_string()),
+            // Timed out io can carry path in message, so text is fixed
+            ErrorKind::Timeout => Response::new_client_error(REQUEST_TIMEOUT.to_string()),
+            ErrorKind::NotFound => Response::new_client_error(NOT_FOUND.to_string()),
+            ErrorKind::Forbidden => Response::new_client_error(NOT_ALLOWED.to_string()),
+            ErrorKind::Upstream => Response::new_server_error(SCRIPT_ERROR.to_string()),
+            ErrorKind::Io | ErrorKind::Other | ErrorKind::Unexpected => {
+                Response::new_server_error(INTERNAL_ERROR.to_string())
+            }
+        }
+    }
+
     pub fn render_header(&self) -> Vec<u8> {
-        let line = format!("{} {}\r\n", self.status_code.int_value(), self.status_line);
+        let line = format!("{} {}\r\n", self.status_code as u8, self.status_line);
 
         line.as_bytes().to_vec()
     }
@@ -110,3 +155,45 @@ fn render_header_redirect() {
 
     assert_eq!(result, expect);
 }
+
+#[test]
+fn from_error_hides_details() {
+    let missing = Error::new_not_found("/srv/localhost/missing.gmi");
+    let denied = Error::new_forbidden("/srv/localhost/private");
+    let broken = Error::new_io("No space left on device (os error 28)");
+    let script = Error::new_upstream("Can't run /srv/localhost/app.cgi");
+    let slow = Error::new_timeout("Can't open /srv/localhost/nfs/file.gmi");
+
+    assert_eq!(
+        Response::from_error(&missing).render_header(),
+        b"4 Not found\r\n"
+    );
+    assert_eq!(
+        Response::from_error(&denied).render_header(),
+        b"4 Not allowed\r\n"
+    );
+    assert_eq!(
+        Response::from_error(&broken).render_header(),
+        b"5 Internal server error\r\n"
+    );
+    assert_eq!(
+        Response::from_error(&script).render_header(),
+        b"5 Script error\r\n"
+    );
+    assert_eq!(
+        Response::from_error(&slow).render_header(),
+        b"4 Request timeout\r\n"
+    );
+}
+
+#[test]
+fn from_error_keeps_request_messages() {
+    assert_eq!(
+        Response::from_error(&Error::new_bad_request("Upload too big")),
+        Response::new_client_error("Upload too big".to_string())
+    );
+    assert_eq!(
+        Response::from_error(&Error::new_timeout("Request timeout")),
+        Response::new_client_error("Request timeout".to_string())
+    );
+}
@@ -0,0 +1,47 @@
+use socket2::{Domain, Socket, Type};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+#[test]
+fn serves_socket_passed_by_systemd() {
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    socket
+        .bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into())
+        .unwrap();
+    socket.listen(16).unwrap();
+    // Descriptor should survive exec
+    socket.set_cloexec(false).unwrap();
+
+    let addr = socket.local_addr().unwrap().as_socket().unwrap();
+
+    // Like systemd: socket is moved to descriptor 3 and LISTEN_PID is pid of server itself
+    let mut server = Command::new("sh")
+        .arg("-c")
+        .arg("export LISTEN_PID=$$ LISTEN_FDS=1; exec \"$0\" --root ./example 3<&\"$1\"")
+        .arg(env!("CARGO_BIN_EXE_rustan"))
+        .arg(socket.as_raw_fd().to_string())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    drop(socket);
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    client.write_all(b"localhost / 0\r\n").unwrap();
+
+    let mut response = Vec::new();
+    let result = client.read_to_end(&mut response);
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+
+    result.unwrap();
+    assert!(response.starts_with(b"2 text/gemini\r\n"));
+}